@@ -4,6 +4,7 @@ extern crate naam;
 
 use naam::builder::{Build, Builder};
 use naam::builtins::Nop;
+use naam::cfg::Branches;
 use naam::cpu::DirectThreadedLoop as Cpu;
 use naam::debug_info::Dump;
 use naam::tape::UnexpectedEndError;
@@ -15,12 +16,8 @@ fn main() {
     let code = SayItNTimes(&hello);
     let program = Program::new(Cpu, vec![], &code).unwrap();
     println!("{:#?}\n", program);
-    let mut ram = SayItNTimesRam {
-        rval: 0,
-        counter: 2,
-    };
-    program.run(&mut ram);
-    assert!(ram.rval == 42);
+    let mut ram = SayItNTimesRam { counter: 2 };
+    assert!(program.run(&mut ram).unwrap() == 42);
 }
 
 #[derive(Debug)]
@@ -28,11 +25,12 @@ struct SayItNTimes<'a>(&'a str);
 
 impl<'a> Build<Cpu> for SayItNTimes<'a> {
     type Ram = SayItNTimesRam;
+    type Output = usize;
     type Error = UnexpectedEndError;
 
     fn build<'tape, 'code>(
         &'code self,
-        builder: &mut Builder<'tape, 'code, Cpu, SayItNTimesRam>,
+        builder: &mut Builder<'tape, 'code, Cpu, SayItNTimesRam, usize>,
     ) -> Result<(), Self::Error>
     where
         'code: 'tape,
@@ -47,49 +45,70 @@ impl<'a> Build<Cpu> for SayItNTimes<'a> {
 
 #[derive(Clone, Copy, Debug)]
 struct SayItNTimesRam {
-    rval: usize,
     counter: usize,
 }
 
-#[derive(Clone, Copy, Debug, Dump)]
+#[derive(Branches, Clone, Copy, Debug, Dump)]
+#[naam(falls_through = false)]
 struct Return(usize);
 
-impl<'tape> Execute<'tape, SayItNTimesRam> for Return {
+impl<'tape, Ram> Execute<'tape, Ram, usize> for Return
+where
+    Ram: ?Sized,
+{
+    #[inline(always)]
+    fn as_branches(&self) -> Option<&dyn Branches<'tape>> {
+        Some(self)
+    }
+
     fn execute(
         pc: Pc<'tape, Self>,
         runner: Runner<'tape>,
-        ram: &mut SayItNTimesRam,
-    ) -> Destination<'tape> {
-        ram.rval = pc.0;
-        Err(runner.halt())
+        _ram: &mut Ram,
+    ) -> Destination<'tape, usize> {
+        Err(runner.halt_with(pc.0))
     }
 }
 
-#[derive(Clone, Copy, Debug, Dump)]
+#[derive(Branches, Clone, Copy, Debug, Dump)]
 #[repr(transparent)]
 struct PrintLn<'code>(&'code str);
 
-impl<'tape, 'code: 'tape, Ram> Execute<'tape, Ram> for PrintLn<'code>
+impl<'tape, 'code: 'tape, Ram, Out> Execute<'tape, Ram, Out> for PrintLn<'code>
 where
     Ram: ?Sized,
 {
     #[inline(always)]
-    fn execute(pc: Pc<'tape, Self>, _runner: Runner<'tape>, _ram: &mut Ram) -> Destination<'tape> {
+    fn as_branches(&self) -> Option<&dyn Branches<'tape>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn execute(
+        pc: Pc<'tape, Self>,
+        _runner: Runner<'tape>,
+        _ram: &mut Ram,
+    ) -> Destination<'tape, Out> {
         println!("{}", pc.0);
         Ok(pc.next())
     }
 }
 
-#[derive(Clone, Copy, Dump)]
+#[derive(Branches, Clone, Copy, Dump)]
 #[repr(transparent)]
 struct JumpNTimes<'tape>(Offset<'tape>);
 
-impl<'tape, 'code> Execute<'tape, SayItNTimesRam> for JumpNTimes<'tape> {
+impl<'tape, Out> Execute<'tape, SayItNTimesRam, Out> for JumpNTimes<'tape> {
+    #[inline(always)]
+    fn as_branches(&self) -> Option<&dyn Branches<'tape>> {
+        Some(self)
+    }
+
     fn execute(
         pc: Pc<'tape, Self>,
         runner: Runner<'tape>,
         ram: &mut SayItNTimesRam,
-    ) -> Destination<'tape> {
+    ) -> Destination<'tape, Out> {
         Ok(if ram.counter > 0 {
             ram.counter -= 1;
             runner.resolve_offset(pc.0)
@@ -0,0 +1,332 @@
+use proc_macro2::{Span, TokenStream};
+use quote::{quote, ToTokens};
+use syn::ext::IdentExt;
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::{
+    braced, parenthesized, Expr, ExprPath, GenericArgument, Generics, Ident, Index, Member, Path,
+    PathArguments, Stmt, Token, Type,
+};
+
+pub(crate) fn expand(input: Asm) -> syn::Result<TokenStream> {
+    let Asm {
+        generics,
+        trait_,
+        self_ty,
+        ram,
+        out,
+        error,
+        listing,
+    } = input;
+
+    let cpu = match trait_.segments.last().map(|segment| &segment.arguments) {
+        Some(PathArguments::AngleBracketed(args)) if args.args.len() == 1 => match &args.args[0] {
+            GenericArgument::Type(cpu) => cpu,
+            arg => return Err(syn::Error::new_spanned(arg, "expected a CPU type")),
+        },
+        _ => return Err(syn::Error::new_spanned(&trait_, "expected `Build<Cpu>`")),
+    };
+    let ram = ram.ok_or_else(|| syn::Error::new(Span::call_site(), "missing `type Ram`"))?;
+    let out = out.unwrap_or_else(|| syn::parse_quote!(()));
+    let error = error.unwrap_or_else(|| syn::parse_quote!(naam::tape::UnexpectedEndError));
+
+    // Labels are all created upfront, so that operations can refer to the
+    // ones defined after them.
+    let mut labels = Vec::<&Ident>::new();
+    for line in &listing {
+        if let Line::Label(name) = line {
+            if labels.contains(&name) {
+                return Err(syn::Error::new_spanned(
+                    name,
+                    format!("label `{}` is defined twice", name),
+                ));
+            }
+            labels.push(name);
+        }
+    }
+
+    let builder = Ident::new("builder", Span::mixed_site());
+    let label_vars = labels
+        .iter()
+        .map(|name| label_var(name))
+        .collect::<Vec<_>>();
+    let label_names = labels.iter().map(|name| name.to_string());
+
+    let mut bound = Vec::<&Ident>::new();
+    let mut body = vec![];
+    for line in &listing {
+        body.push(match line {
+            Line::Label(name) => {
+                bound.push(name);
+                let var = label_var(name);
+                quote! { #builder.bind(&mut #var); }
+            }
+            Line::Stmt(stmt) => stmt.to_token_stream(),
+            Line::Op(op) => op.expand(&builder, &labels, &bound)?,
+        });
+    }
+
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics #trait_ for #self_ty #where_clause {
+            type Ram = #ram;
+            type Output = #out;
+            type Error = #error;
+
+            fn build<'tape, 'code>(
+                &'code self,
+                #builder: &mut naam::builder::Builder<'tape, 'code, #cpu, #ram, #out>,
+            ) -> core::result::Result<(), Self::Error>
+            where
+                'code: 'tape,
+            {
+                #(let mut #label_vars = #builder.named_label(#label_names);)*
+                #(#body)*
+                Ok(())
+            }
+        }
+    })
+}
+
+/// Returns the variable storing the label with the given name.
+fn label_var(name: &Ident) -> Ident {
+    Ident::new(&format!("label_{}", name), Span::mixed_site())
+}
+
+/// The input of `asm!`.
+pub(crate) struct Asm {
+    generics: Generics,
+    trait_: Path,
+    self_ty: Type,
+    ram: Option<Type>,
+    out: Option<Type>,
+    error: Option<Type>,
+    listing: Vec<Line>,
+}
+
+impl Parse for Asm {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        input.parse::<Token![impl]>()?;
+        let mut generics = input.parse::<Generics>()?;
+        let trait_ = input.parse()?;
+        input.parse::<Token![for]>()?;
+        let self_ty = input.parse()?;
+        generics.where_clause = input.parse()?;
+
+        let content;
+        braced!(content in input);
+        let (mut ram, mut out, mut error) = (None, None, None);
+        while content.peek(Token![type]) {
+            content.parse::<Token![type]>()?;
+            let name = content.parse::<Ident>()?;
+            content.parse::<Token![=]>()?;
+            let ty = content.parse::<Type>()?;
+            content.parse::<Token![;]>()?;
+            let slot = if name == "Ram" {
+                &mut ram
+            } else if name == "Output" {
+                &mut out
+            } else if name == "Error" {
+                &mut error
+            } else {
+                return Err(syn::Error::new(name.span(), "unknown associated type"));
+            };
+            if slot.replace(ty).is_some() {
+                return Err(syn::Error::new(
+                    name.span(),
+                    format!("duplicate `type {}`", name),
+                ));
+            }
+        }
+
+        let mut listing = vec![];
+        while !content.is_empty() {
+            if content.peek(Token![let]) {
+                listing.push(Line::Stmt(content.parse()?));
+            } else if content.peek(Ident::peek_any)
+                && content.peek2(Token![:])
+                && !content.peek2(Token![::])
+            {
+                listing.push(Line::Label(content.call(Ident::parse_any)?));
+                content.parse::<Token![:]>()?;
+            } else {
+                listing.push(Line::Op(content.parse()?));
+                if !content.is_empty() {
+                    content.parse::<Token![;]>()?;
+                }
+            }
+        }
+
+        Ok(Self {
+            generics,
+            trait_,
+            self_ty,
+            ram,
+            out,
+            error,
+            listing,
+        })
+    }
+}
+
+/// A line of the listing.
+enum Line {
+    /// The definition of a label, such as `loop:`.
+    Label(Ident),
+    /// A `let` statement.
+    Stmt(Stmt),
+    /// An operation, such as `JumpNTimes(loop)`.
+    Op(Op),
+}
+
+/// An operation, whose operands may refer to labels.
+struct Op {
+    path: ExprPath,
+    operands: Operands,
+}
+
+enum Operands {
+    Unit,
+    Tuple(Punctuated<Operand, Token![,]>),
+    Struct(Punctuated<(Member, Operand), Token![,]>),
+}
+
+/// An operand, which refers to a label if it is the name of one.
+enum Operand {
+    Ident(Ident),
+    Expr(Box<Expr>),
+}
+
+impl Op {
+    fn expand(
+        &self,
+        builder: &Ident,
+        labels: &[&Ident],
+        bound: &[&Ident],
+    ) -> syn::Result<TokenStream> {
+        let members = match &self.operands {
+            Operands::Unit => vec![],
+            Operands::Tuple(operands) => operands
+                .iter()
+                .enumerate()
+                .map(|(i, operand)| (Member::Unnamed(Index::from(i)), operand))
+                .collect(),
+            Operands::Struct(operands) => operands
+                .iter()
+                .map(|(member, operand)| (member.clone(), operand))
+                .collect(),
+        };
+
+        // A single label defined after the operation can be patched when it
+        // is bound, the other ones are already bound.
+        let references = members
+            .iter()
+            .filter_map(|(member, operand)| Some((member, operand.label(labels)?)))
+            .collect::<Vec<_>>();
+        let mut forward = references
+            .iter()
+            .filter(|(_, label)| !bound.contains(label));
+        let patched = forward.next().copied();
+        if let Some((_, label)) = forward.next() {
+            return Err(syn::Error::new_spanned(
+                label,
+                "an operation can only refer to a single label defined after it",
+            ));
+        }
+
+        let offset = Ident::new("offset", Span::mixed_site());
+        let operands = members.iter().map(|(member, operand)| {
+            let value = match (operand.label(labels), patched) {
+                (Some(_), Some((patched, _))) if patched == member => offset.to_token_stream(),
+                (Some(label), _) => {
+                    let var = label_var(label);
+                    quote! { #var.offset().unwrap() }
+                }
+                (None, _) => match operand {
+                    Operand::Ident(ident) => ident.to_token_stream(),
+                    Operand::Expr(expr) => expr.to_token_stream(),
+                },
+            };
+            // Struct expressions name every member, even numeric ones such
+            // as in `Jump { 0: end }`.
+            match self.operands {
+                Operands::Struct(_) => quote! { #member: #value },
+                Operands::Unit | Operands::Tuple(_) => value,
+            }
+        });
+        let path = &self.path;
+        let op = match self.operands {
+            Operands::Unit => quote! { #path },
+            Operands::Tuple(_) => quote! { #path(#(#operands),*) },
+            Operands::Struct(_) => quote! { #path { #(#operands),* } },
+        };
+
+        Ok(match patched {
+            Some((member, label)) => {
+                let var = label_var(label);
+                let op_var = Ident::new("op", Span::mixed_site());
+                quote! {
+                    #builder.emit_with_label(
+                        &#var,
+                        |#offset| #op,
+                        |#op_var| &mut #op_var.#member,
+                    )?;
+                }
+            }
+            None => quote! { #builder.emit(#op)?; },
+        })
+    }
+}
+
+impl Parse for Op {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let path = input.parse()?;
+        let operands = if input.peek(syn::token::Paren) {
+            let content;
+            parenthesized!(content in input);
+            Operands::Tuple(content.parse_terminated(Operand::parse)?)
+        } else if input.peek(syn::token::Brace) {
+            let content;
+            braced!(content in input);
+            Operands::Struct(content.parse_terminated(|input| {
+                let member = input.parse::<Member>()?;
+                if input.peek(Token![:]) {
+                    input.parse::<Token![:]>()?;
+                    return Ok((member, input.parse()?));
+                }
+                match member {
+                    Member::Named(name) => Ok((Member::Named(name.clone()), Operand::Ident(name))),
+                    Member::Unnamed(index) => Err(syn::Error::new_spanned(index, "expected `:`")),
+                }
+            })?)
+        } else {
+            Operands::Unit
+        };
+        Ok(Self { path, operands })
+    }
+}
+
+impl Operand {
+    /// Returns the label this operand refers to, if any.
+    fn label(&self, labels: &[&Ident]) -> Option<&Ident> {
+        match self {
+            Operand::Ident(ident) if labels.contains(&ident) => Some(ident),
+            _ => None,
+        }
+    }
+}
+
+impl Parse for Operand {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        // Labels may be named after keywords, such as `loop`.
+        let fork = input.fork();
+        if let Ok(ident) = fork.call(Ident::parse_any) {
+            if fork.is_empty() || fork.peek(Token![,]) {
+                input.call(Ident::parse_any)?;
+                return Ok(Operand::Ident(ident));
+            }
+        }
+        Ok(Operand::Expr(input.parse()?))
+    }
+}
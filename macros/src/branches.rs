@@ -0,0 +1,237 @@
+use proc_macro2::{Span, TokenStream, TokenTree};
+use quote::quote;
+use std::borrow::Cow;
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::{parse_quote, Attribute, Data, Ident, Lifetime, Lit, Token, Type};
+use synstructure::{AddBounds, BindingInfo, Structure};
+
+pub(crate) fn derive(mut s: Structure) -> TokenStream {
+    match try_derive(&mut s) {
+        Ok(tokens) => tokens,
+        Err(e) => e.to_compile_error(),
+    }
+}
+
+fn try_derive(s: &mut Structure) -> syn::Result<TokenStream> {
+    let container = Options::parse(&s.ast().attrs)?;
+    if container.branch.is_some() {
+        return Err(syn::Error::new_spanned(
+            &s.ast().ident,
+            "`branch` can only be used on fields",
+        ));
+    }
+
+    // The tape lifetime can also be given to `#[derive(Dump)]`, so that both
+    // derives agree on it without repeating it.
+    let tape_lt = match container.tape.or(dump_tape_lifetime(&s.ast().attrs)?) {
+        Some(lt) => s
+            .ast()
+            .generics
+            .lifetimes()
+            .find(|def| def.lifetime == lt)
+            .map_or_else(
+                || Cow::Owned(lt.clone()),
+                |def| Cow::Borrowed(&def.lifetime),
+            ),
+        None => s
+            .ast()
+            .generics
+            .lifetimes()
+            .find(|def| def.lifetime.ident == "tape")
+            .map_or_else(
+                || Cow::Owned(Lifetime::new("'tape", Span::call_site())),
+                |def| Cow::Borrowed(&def.lifetime),
+            ),
+    };
+    let generated_tape_lt = match &tape_lt {
+        Cow::Owned(lt) => Some(lt.clone()),
+        Cow::Borrowed(_) => None,
+    };
+    let tape_lt = tape_lt.into_owned();
+
+    let mut falls_through_arms = vec![];
+    let mut branch_fields = vec![];
+    for v in s.variants() {
+        let options = Options::parse(v.ast().attrs)?;
+        if options.branch.is_some() {
+            return Err(syn::Error::new_spanned(
+                v.ast().ident,
+                "`branch` can only be used on fields",
+            ));
+        }
+        // The variant of a struct has the same attributes as the struct.
+        if let (Data::Enum(_), Some(tape)) = (&s.ast().data, &options.tape) {
+            return Err(syn::Error::new_spanned(
+                tape,
+                "`tape` can only be used on types",
+            ));
+        }
+        let falls_through = options
+            .falls_through
+            .or(container.falls_through)
+            .unwrap_or(true);
+        let pat = v.pat();
+        falls_through_arms.push(quote! { #pat => #falls_through, });
+
+        for b in v.bindings() {
+            let options = Options::parse(&b.ast().attrs)?;
+            if options.falls_through.is_some() {
+                return Err(syn::Error::new_spanned(
+                    b.ast(),
+                    "`falls_through` can only be used on types and variants",
+                ));
+            }
+            if let Some(tape) = &options.tape {
+                return Err(syn::Error::new_spanned(
+                    tape,
+                    "`tape` can only be used on types",
+                ));
+            }
+            branch_fields.push(options.branch.unwrap_or_else(|| is_offset(&b.ast().ty)));
+        }
+    }
+
+    // Only the fields holding branches need to implement the trait.
+    s.add_bounds(AddBounds::None);
+    let mut fields = branch_fields.iter().copied();
+    s.filter(|_| fields.next().unwrap());
+    let bounded_types = s
+        .variants()
+        .iter()
+        .flat_map(|v| v.bindings())
+        .filter(|b| !b.referenced_ty_params().is_empty())
+        .map(|b| b.ast().ty.clone())
+        .collect::<Vec<_>>();
+    for ty in bounded_types {
+        s.add_where_predicate(parse_quote!(#ty: naam::cfg::Branches<#tape_lt>));
+    }
+
+    let branches = s.each(|b: &BindingInfo| {
+        quote! { naam::cfg::Branches::branches(#b, branch) }
+    });
+
+    Ok(s.underscore_const(true).gen_impl(quote! {
+        gen impl<#generated_tape_lt> naam::cfg::Branches<#tape_lt> for @Self {
+            #[allow(unused_variables)]
+            #[inline(always)]
+            fn falls_through(&self) -> bool {
+                match *self { #(#falls_through_arms)* }
+            }
+
+            #[allow(unused_variables)]
+            #[inline(always)]
+            fn branches(&self, branch: &mut dyn FnMut(naam::Offset<#tape_lt>)) {
+                match *self { #branches }
+            }
+        }
+    }))
+}
+
+/// Returns whether the given type is `Offset<'tape>`, whatever the path
+/// used to name it.
+fn is_offset(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) if path.qself.is_none() => path
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "Offset"),
+        Type::Group(group) => is_offset(&group.elem),
+        Type::Paren(paren) => is_offset(&paren.elem),
+        _ => false,
+    }
+}
+
+/// The options given through `#[naam(...)]` attributes.
+#[derive(Default)]
+struct Options {
+    falls_through: Option<bool>,
+    branch: Option<bool>,
+    tape: Option<Lifetime>,
+}
+
+impl Options {
+    fn parse(attrs: &[Attribute]) -> syn::Result<Self> {
+        let mut options = Self::default();
+        for attr in attrs.iter().filter(|attr| attr.path.is_ident("naam")) {
+            let args = attr.parse_args_with(Punctuated::<Arg, Token![,]>::parse_terminated)?;
+            for arg in args {
+                let (name, duplicate) = match arg {
+                    Arg::FallsThrough(name, value) => {
+                        (name, options.falls_through.replace(value).is_some())
+                    }
+                    Arg::Branch(name, value) => (name, options.branch.replace(value).is_some()),
+                    Arg::Tape(name, value) => (name, options.tape.replace(value).is_some()),
+                };
+                if duplicate {
+                    return Err(syn::Error::new(
+                        name.span(),
+                        format!("duplicate `{}` option", name),
+                    ));
+                }
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// A single option in a `#[naam(...)]` attribute.
+enum Arg {
+    FallsThrough(Ident, bool),
+    Branch(Ident, bool),
+    Tape(Ident, Lifetime),
+}
+
+impl Parse for Arg {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse::<Ident>()?;
+        if name != "falls_through" && name != "branch" && name != "tape" {
+            return Err(syn::Error::new(name.span(), "unknown option"));
+        }
+        if name == "branch" && !input.peek(Token![=]) {
+            return Ok(Arg::Branch(name, true));
+        }
+        input.parse::<Token![=]>()?;
+        if name == "tape" {
+            return Ok(Arg::Tape(name, input.parse()?));
+        }
+        let value = match input.parse::<Lit>()? {
+            Lit::Bool(lit) => lit.value,
+            lit => return Err(syn::Error::new_spanned(lit, "expected a boolean")),
+        };
+        Ok(if name == "falls_through" {
+            Arg::FallsThrough(name, value)
+        } else {
+            Arg::Branch(name, value)
+        })
+    }
+}
+
+/// Returns the lifetime given by a `#[dump(tape = 'a)]` attribute, if any,
+/// skipping the other options of `#[derive(Dump)]`.
+fn dump_tape_lifetime(attrs: &[Attribute]) -> syn::Result<Option<Lifetime>> {
+    let mut tape = None;
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("dump")) {
+        attr.parse_args_with(|input: ParseStream| {
+            while !input.is_empty() {
+                if input.peek(Ident) && input.peek2(Token![=]) && input.peek3(Lifetime) {
+                    let name = input.parse::<Ident>()?;
+                    input.parse::<Token![=]>()?;
+                    let lt = input.parse::<Lifetime>()?;
+                    if name == "tape" {
+                        tape = Some(lt);
+                    }
+                }
+                while !input.is_empty() && !input.peek(Token![,]) {
+                    input.parse::<TokenTree>()?;
+                }
+                if !input.is_empty() {
+                    input.parse::<Token![,]>()?;
+                }
+            }
+            Ok(())
+        })?;
+    }
+    Ok(tape)
+}
@@ -1,20 +1,43 @@
 use proc_macro2::{Span, TokenStream};
 use quote::quote;
 use std::borrow::Cow;
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
 use syn::visit;
-use syn::{Fields, Lifetime};
-use synstructure::{BindingInfo, Structure};
+use syn::{parse_quote, Attribute, Data, Fields, Ident, Lifetime, LitStr, Path, Token};
+use synstructure::{AddBounds, BindingInfo, Structure, VariantInfo};
 
 pub(crate) fn derive(mut s: Structure) -> TokenStream {
-    let tape_lt = s
-        .ast()
-        .generics
-        .lifetimes()
-        .find(|def| def.lifetime.ident == "tape")
-        .map_or_else(
-            || Cow::Owned(Lifetime::new("'tape", Span::call_site())),
-            |def| Cow::Borrowed(&def.lifetime),
-        );
+    match try_derive(&mut s) {
+        Ok(tokens) => tokens,
+        Err(e) => e.to_compile_error(),
+    }
+}
+
+fn try_derive(s: &mut Structure) -> syn::Result<TokenStream> {
+    let container = Options::parse(&s.ast().attrs)?;
+    container.check(&[TYPES, TYPES_AND_VARIANTS])?;
+
+    let tape_lt = match &container.tape {
+        Some(lt) => s
+            .ast()
+            .generics
+            .lifetimes()
+            .find(|def| def.lifetime == *lt)
+            .map_or_else(
+                || Cow::Owned(lt.clone()),
+                |def| Cow::Borrowed(&def.lifetime),
+            ),
+        None => s
+            .ast()
+            .generics
+            .lifetimes()
+            .find(|def| def.lifetime.ident == "tape")
+            .map_or_else(
+                || Cow::Owned(Lifetime::new("'tape", Span::call_site())),
+                |def| Cow::Borrowed(&def.lifetime),
+            ),
+    };
 
     let (builtin_tape_lt, generated_tape_lt) = match &tape_lt {
         Cow::Owned(lt) => (None, Some(lt)),
@@ -22,57 +45,68 @@ pub(crate) fn derive(mut s: Structure) -> TokenStream {
     };
 
     let has_type_params = s.ast().generics.type_params().next().is_some();
+    if container.mnemonic.is_some() && s.variants().len() != 1 {
+        return Err(syn::Error::new_spanned(
+            &s.ast().ident,
+            "`mnemonic` must be used on each variant of an enum",
+        ));
+    }
 
-    let body = if builtin_tape_lt.is_none() && !has_type_params {
+    let mut variants = vec![];
+    let mut has_options = container.mnemonic.is_some();
+    for v in s.variants() {
+        // The variant of a struct has the same attributes as the struct.
+        let options = match s.ast().data {
+            Data::Enum(_) => Options::parse(v.ast().attrs)?,
+            _ => Options::default(),
+        };
+        options.check(&[TYPES_AND_VARIANTS])?;
+        let mnemonic = options.mnemonic.or_else(|| container.mnemonic.clone());
+        let mut fields = vec![];
+        for b in v.bindings() {
+            let options = Options::parse(&b.ast().attrs)?;
+            options.check_field(b)?;
+            has_options |= options.skip || options.rename.is_some() || options.with.is_some();
+            fields.push(options);
+        }
+        has_options |= mnemonic.is_some();
+        variants.push((v, mnemonic, fields));
+    }
+
+    let body = if builtin_tape_lt.is_none() && !has_type_params && !has_options {
         quote! { core::fmt::Debug::fmt(self, fmt) }
     } else {
         let mut type_classifier = TypeClassifier::new(builtin_tape_lt);
 
-        let match_arms = s.variants().iter().map(|v| {
-            let ctor = v.ast().ident.to_string();
+        let match_arms = variants.iter().map(|(v, mnemonic, fields)| {
             let pat = v.pat();
-            let expr = match v.ast().fields {
-                Fields::Named(..) => {
-                    let unfinished = v.bindings().iter().fold(
-                        quote! { core::fmt::Formatter::debug_struct(fmt, #ctor) },
-                        |acc, b| {
-                            let name = b.ast().ident.as_ref().unwrap().to_string();
-                            let field_expr = type_classifier.field_expr(b);
-                            quote! {
-                                core::fmt::DebugStruct::field(
-                                    &mut #acc,
-                                    #name,
-                                    #field_expr
-                                )
-                            }
-                        },
-                    );
-                    quote! { core::fmt::DebugStruct::finish(#unfinished) }
-                }
-                Fields::Unnamed(..) => {
-                    let unfinished = v.bindings().iter().fold(
-                        quote! { core::fmt::Formatter::debug_tuple(fmt, #ctor) },
-                        |acc, b| {
-                            let field_expr = type_classifier.field_expr(b);
-                            quote! {
-                                core::fmt::DebugTuple::field(
-                                    &mut #acc,
-                                    #field_expr
-                                )
-                            }
-                        },
-                    );
-                    quote! { core::fmt::DebugTuple::finish(#unfinished) }
-                }
-                Fields::Unit => quote! { fmt.write_str(#ctor) },
+            let expr = match mnemonic {
+                Some(mnemonic) => mnemonic_expr(v, mnemonic, fields, &mut type_classifier),
+                None => debug_expr(v, fields, &mut type_classifier),
             };
-            quote! { #pat => #expr }
+            quote! { #pat => #expr, }
         });
         quote! { match *self { #(#match_arms)* } }
     };
 
-    s.underscore_const(true).gen_impl(quote! {
+    // Only the fields dumped through the bridge need to implement the trait,
+    // the bounds and where clauses of the type itself are kept as is.
+    let bounded_types = variants
+        .iter()
+        .flat_map(|(v, _, fields)| v.bindings().iter().zip(fields))
+        .filter(|(b, options)| {
+            !options.skip && options.with.is_none() && !b.referenced_ty_params().is_empty()
+        })
+        .map(|(b, _)| b.ast().ty.clone())
+        .collect::<Vec<_>>();
+    s.add_bounds(AddBounds::None);
+    for ty in bounded_types {
+        s.add_where_predicate(parse_quote!(#ty: naam::debug_info::Dump<#tape_lt>));
+    }
+
+    Ok(s.underscore_const(true).gen_impl(quote! {
         gen impl<#generated_tape_lt> naam::debug_info::Dump<#tape_lt> for @Self {
+            #[allow(unused_variables)]
             fn dump(
                 &self,
                 fmt: &mut core::fmt::Formatter,
@@ -81,8 +115,90 @@ pub(crate) fn derive(mut s: Structure) -> TokenStream {
                 #body
             }
         }
-    })
+    }))
+}
+
+/// Dumps a variant like `#[derive(Debug)]` does.
+fn debug_expr(
+    v: &VariantInfo<'_>,
+    fields: &[Options],
+    type_classifier: &mut TypeClassifier<'_>,
+) -> TokenStream {
+    let ctor = v.ast().ident.to_string();
+    let bindings = v
+        .bindings()
+        .iter()
+        .zip(fields)
+        .filter(|(_, options)| !options.skip);
+    match v.ast().fields {
+        Fields::Named(..) => {
+            let unfinished = bindings.fold(
+                quote! { core::fmt::Formatter::debug_struct(fmt, #ctor) },
+                |acc, (b, options)| {
+                    let name = match &options.rename {
+                        Some(rename) => rename.value(),
+                        None => b.ast().ident.as_ref().unwrap().to_string(),
+                    };
+                    let field_expr = type_classifier.field_expr(b, options);
+                    quote! {
+                        core::fmt::DebugStruct::field(
+                            &mut #acc,
+                            #name,
+                            #field_expr
+                        )
+                    }
+                },
+            );
+            quote! { core::fmt::DebugStruct::finish(#unfinished) }
+        }
+        Fields::Unnamed(..) => {
+            let unfinished = bindings.fold(
+                quote! { core::fmt::Formatter::debug_tuple(fmt, #ctor) },
+                |acc, (b, options)| {
+                    let field_expr = type_classifier.field_expr(b, options);
+                    quote! {
+                        core::fmt::DebugTuple::field(
+                            &mut #acc,
+                            #field_expr
+                        )
+                    }
+                },
+            );
+            quote! { core::fmt::DebugTuple::finish(#unfinished) }
+        }
+        Fields::Unit => quote! { fmt.write_str(#ctor) },
+    }
 }
+
+/// Dumps a variant as its mnemonic followed by its operands, separated by
+/// commas, like `jmp L3`.
+fn mnemonic_expr(
+    v: &VariantInfo<'_>,
+    mnemonic: &LitStr,
+    fields: &[Options],
+    type_classifier: &mut TypeClassifier<'_>,
+) -> TokenStream {
+    let operands = v
+        .bindings()
+        .iter()
+        .zip(fields)
+        .filter(|(_, options)| !options.skip)
+        .enumerate()
+        .map(|(i, (b, options))| {
+            let separator = if i == 0 { " " } else { ", " };
+            let field_expr = type_classifier.field_expr(b, options);
+            quote! {
+                fmt.write_str(#separator)?;
+                core::fmt::Debug::fmt(#field_expr, fmt)?;
+            }
+        });
+    quote! {{
+        fmt.write_str(#mnemonic)?;
+        #(#operands)*
+        Ok(())
+    }}
+}
+
 struct TypeClassifier<'a> {
     tape_lt: Option<&'a Lifetime>,
     should_use_debug_bridge: bool,
@@ -96,7 +212,10 @@ impl<'a> TypeClassifier<'a> {
         }
     }
 
-    fn field_expr(&mut self, b: &BindingInfo<'_>) -> TokenStream {
+    fn field_expr(&mut self, b: &BindingInfo<'_>, options: &Options) -> TokenStream {
+        if let Some(with) = &options.with {
+            return quote! { &naam::debug_info::Dumper::debug_with(dumper, #b, #with) };
+        }
         if b.referenced_ty_params().is_empty() && !self.has_tape_lifetime_param(b) {
             return quote! { #b };
         }
@@ -120,3 +239,108 @@ impl<'ast> visit::Visit<'ast> for TypeClassifier<'_> {
         visit::visit_lifetime(self, lt);
     }
 }
+
+/// The options given through `#[dump(...)]` attributes.
+#[derive(Default)]
+struct Options {
+    skip: bool,
+    rename: Option<LitStr>,
+    with: Option<Path>,
+    mnemonic: Option<LitStr>,
+    tape: Option<Lifetime>,
+    /// The names of the options that were given, with the kind of items
+    /// they can be used on.
+    given: Vec<(Ident, &'static str)>,
+}
+
+impl Options {
+    fn parse(attrs: &[Attribute]) -> syn::Result<Self> {
+        let mut options = Self::default();
+        for attr in attrs.iter().filter(|attr| attr.path.is_ident("dump")) {
+            let args = attr.parse_args_with(Punctuated::<Arg, Token![,]>::parse_terminated)?;
+            for arg in args {
+                let (name, duplicate) = match arg {
+                    Arg::Skip(name) => (name, std::mem::replace(&mut options.skip, true)),
+                    Arg::Rename(name, value) => (name, options.rename.replace(value).is_some()),
+                    Arg::With(name, value) => (name, options.with.replace(value).is_some()),
+                    Arg::Mnemonic(name, value) => (name, options.mnemonic.replace(value).is_some()),
+                    Arg::Tape(name, value) => (name, options.tape.replace(value).is_some()),
+                };
+                if duplicate {
+                    return Err(syn::Error::new(
+                        name.span(),
+                        format!("duplicate `{}` option", name),
+                    ));
+                }
+                let kind = if name == "tape" {
+                    TYPES
+                } else if name == "mnemonic" {
+                    TYPES_AND_VARIANTS
+                } else {
+                    FIELDS
+                };
+                options.given.push((name, kind));
+            }
+        }
+        Ok(options)
+    }
+
+    /// Checks that only options for fields were given, and that they make
+    /// sense for the given field.
+    fn check_field(&self, b: &BindingInfo<'_>) -> syn::Result<()> {
+        self.check(&[FIELDS])?;
+        match &self.rename {
+            Some(rename) if b.ast().ident.is_none() => Err(syn::Error::new_spanned(
+                rename,
+                "`rename` can only be used on named fields",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that only options for the given kinds of items were given.
+    fn check(&self, kinds: &[&str]) -> syn::Result<()> {
+        match self.given.iter().find(|(_, kind)| !kinds.contains(kind)) {
+            Some((name, allowed)) => Err(syn::Error::new(
+                name.span(),
+                format!("`{}` can only be used on {}", name, allowed),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A single option in a `#[dump(...)]` attribute.
+enum Arg {
+    Skip(Ident),
+    Rename(Ident, LitStr),
+    With(Ident, Path),
+    Mnemonic(Ident, LitStr),
+    Tape(Ident, Lifetime),
+}
+
+const TYPES: &str = "types";
+const TYPES_AND_VARIANTS: &str = "types and variants";
+const FIELDS: &str = "fields";
+
+impl Parse for Arg {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse::<Ident>()?;
+        if name == "skip" {
+            return Ok(Arg::Skip(name));
+        }
+        if name != "rename" && name != "with" && name != "mnemonic" && name != "tape" {
+            return Err(syn::Error::new(name.span(), "unknown option"));
+        }
+        input.parse::<Token![=]>()?;
+        Ok(if name == "rename" {
+            Arg::Rename(name, input.parse()?)
+        } else if name == "with" {
+            Arg::With(name, input.parse()?)
+        } else if name == "mnemonic" {
+            Arg::Mnemonic(name, input.parse()?)
+        } else {
+            Arg::Tape(name, input.parse()?)
+        })
+    }
+}
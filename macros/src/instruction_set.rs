@@ -0,0 +1,288 @@
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::{
+    parenthesized, Attribute, Block, Fields, FieldsNamed, FieldsUnnamed, GenericParam, Generics,
+    Ident, Lifetime, Pat, Token, Type, Visibility,
+};
+
+mod kw {
+    syn::custom_keyword!(cpu);
+    syn::custom_keyword!(op);
+}
+
+pub(crate) fn expand(input: InstructionSet) -> syn::Result<TokenStream> {
+    let InstructionSet { cpu, ops } = input;
+    let Cpu {
+        attrs,
+        vis,
+        name,
+        set,
+        ram,
+        out,
+    } = cpu;
+    let out = out.unwrap_or_else(|| syn::parse_quote!(()));
+
+    // Opcodes 0 and 1 are taken by `Unreachable` and `Nop`.
+    if ops.len() > usize::from(u16::MAX) - 1 {
+        return Err(syn::Error::new_spanned(&set, "too many operations"));
+    }
+
+    let mut items = vec![];
+    let mut table = vec![];
+    for (opcode, op) in ops.iter().enumerate() {
+        let opcode = opcode as u16 + 2;
+        items.push(op.expand(&set, &ram, &out, opcode)?);
+        let name = &op.name;
+        let lifetimes = op.generics.lifetimes().map(|_| quote! { 'tape });
+        table.push(quote! { naam::cpu::Exec::new::<#name<#(#lifetimes),*>>() });
+    }
+
+    let set_doc = format!("The instruction set of `{}`.", name);
+    let table_name = format_ident!("__{}Table", set);
+    let len = table.len() + 2;
+
+    Ok(quote! {
+        #[doc = #set_doc]
+        #[derive(Clone, Copy, Debug, Default)]
+        #vis struct #set;
+
+        #(#attrs)*
+        #vis type #name = naam::cpu::TokenThreaded<#set>;
+
+        #(#attrs)*
+        #[allow(non_upper_case_globals)]
+        #vis const #name: #name = naam::cpu::TokenThreaded(#set);
+
+        #[doc(hidden)]
+        struct #table_name<'tape>(core::marker::PhantomData<&'tape ()>);
+
+        impl<'tape> #table_name<'tape> {
+            const EXECS: [naam::cpu::Exec<'tape, #ram, #out>; #len] = [
+                naam::cpu::Exec::new::<naam::builtins::Unreachable>(),
+                naam::cpu::Exec::new::<naam::builtins::Nop>(),
+                #(#table,)*
+            ];
+        }
+
+        unsafe impl naam::cpu::InstructionSet<#ram, #out> for #set {
+            #[inline(always)]
+            fn exec<'tape>(self, opcode: u16) -> naam::cpu::Exec<'tape, #ram, #out> {
+                let execs = &#table_name::<'tape>::EXECS;
+                execs[usize::from(opcode)]
+            }
+        }
+
+        unsafe impl<'tape> naam::cpu::Opcode<'tape, naam::builtins::Unreachable, #ram, #out>
+            for #set
+        {
+            const OPCODE: u16 = 0;
+        }
+
+        unsafe impl<'tape> naam::cpu::Opcode<'tape, naam::builtins::Nop, #ram, #out> for #set {
+            const OPCODE: u16 = 1;
+        }
+
+        #(#items)*
+    })
+}
+
+/// The input of `instruction_set!`.
+pub(crate) struct InstructionSet {
+    cpu: Cpu,
+    ops: Vec<Op>,
+}
+
+impl Parse for InstructionSet {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let cpu = input.parse()?;
+        let mut ops = vec![];
+        while !input.is_empty() {
+            ops.push(input.parse()?);
+        }
+        Ok(Self { cpu, ops })
+    }
+}
+
+/// The declaration of the CPU, such as
+/// `pub cpu Calc(CalcSet) for Vec<usize> -> usize;`.
+struct Cpu {
+    attrs: Vec<Attribute>,
+    vis: Visibility,
+    name: Ident,
+    set: Ident,
+    ram: Type,
+    out: Option<Type>,
+}
+
+impl Parse for Cpu {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let attrs = input.call(Attribute::parse_outer)?;
+        let vis = input.parse()?;
+        input.parse::<kw::cpu>()?;
+        let name = input.parse()?;
+        let content;
+        parenthesized!(content in input);
+        let set = content.parse()?;
+        input.parse::<Token![for]>()?;
+        let ram = input.parse()?;
+        let out = if input.peek(Token![->]) {
+            input.parse::<Token![->]>()?;
+            Some(input.parse()?)
+        } else {
+            None
+        };
+        input.parse::<Token![;]>()?;
+        Ok(Self {
+            attrs,
+            vis,
+            name,
+            set,
+            ram,
+            out,
+        })
+    }
+}
+
+/// The definition of an operation, such as
+/// `pub op Push(usize) |pc, runner, ram| { ... }`.
+struct Op {
+    attrs: Vec<Attribute>,
+    vis: Visibility,
+    name: Ident,
+    generics: Generics,
+    fields: Fields,
+    params: Punctuated<Pat, Token![,]>,
+    body: Block,
+}
+
+impl Op {
+    fn expand(&self, set: &Ident, ram: &Type, out: &Type, opcode: u16) -> syn::Result<TokenStream> {
+        let Op {
+            attrs,
+            vis,
+            name,
+            generics,
+            fields,
+            params,
+            body,
+        } = self;
+
+        if let Some(param) = generics
+            .params
+            .iter()
+            .find(|param| !matches!(param, GenericParam::Lifetime(_)))
+        {
+            return Err(syn::Error::new_spanned(
+                param,
+                "operations can only be generic over lifetimes",
+            ));
+        }
+        if params.len() != 3 {
+            return Err(syn::Error::new_spanned(
+                params,
+                "expected three parameters, such as `|pc, runner, ram|`",
+            ));
+        }
+        let (pc, runner, ram_pat) = (&params[0], &params[1], &params[2]);
+
+        let where_clause = &generics.where_clause;
+        let decl = match fields {
+            Fields::Named(fields) => quote! { #name #generics #where_clause #fields },
+            Fields::Unnamed(fields) => quote! { #name #generics #fields #where_clause; },
+            Fields::Unit => quote! { #name #generics #where_clause; },
+        };
+
+        // The operation must outlive the tape, so every lifetime it is
+        // generic over must outlive `'tape`, unless it is `'tape` itself.
+        let tape = Lifetime::new("'tape", Span::call_site());
+        let mut impl_generics = generics.clone();
+        let has_tape = generics.lifetimes().any(|def| def.lifetime == tape);
+        for def in impl_generics.lifetimes_mut() {
+            if def.lifetime != tape {
+                def.bounds.push(tape.clone());
+            }
+        }
+        if !has_tape {
+            impl_generics.params.insert(0, syn::parse_quote!(#tape));
+        }
+        let (impl_generics, _, where_clause) = impl_generics.split_for_impl();
+        let (_, ty_generics, _) = generics.split_for_impl();
+
+        Ok(quote! {
+            #[derive(naam::cfg::Branches, Clone, Copy, Debug, naam::debug_info::Dump)]
+            #(#attrs)*
+            #vis struct #decl
+
+            impl #impl_generics naam::Execute<#tape, #ram, #out> for #name #ty_generics
+            #where_clause
+            {
+                #[inline(always)]
+                fn as_branches(&self) -> Option<&dyn naam::cfg::Branches<#tape>> {
+                    Some(self)
+                }
+
+                #[inline(always)]
+                fn execute(
+                    #pc: naam::Pc<#tape, Self>,
+                    #runner: naam::Runner<#tape>,
+                    #ram_pat: &mut #ram,
+                ) -> naam::Destination<#tape, #out>
+                #body
+            }
+
+            unsafe impl #impl_generics naam::cpu::Opcode<#tape, #name #ty_generics, #ram, #out>
+                for #set
+            #where_clause
+            {
+                const OPCODE: u16 = #opcode;
+            }
+        })
+    }
+}
+
+impl Parse for Op {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let attrs = input.call(Attribute::parse_outer)?;
+        let vis = input.parse()?;
+        input.parse::<kw::op>()?;
+        let name = input.parse()?;
+        let mut generics = input.parse::<Generics>()?;
+        let fields = if input.peek(syn::token::Paren) {
+            Fields::Unnamed(input.parse::<FieldsUnnamed>()?)
+        } else {
+            generics.where_clause = input.parse()?;
+            if input.peek(syn::token::Brace) {
+                Fields::Named(input.parse::<FieldsNamed>()?)
+            } else {
+                Fields::Unit
+            }
+        };
+        if let Fields::Unnamed(_) = fields {
+            generics.where_clause = input.parse()?;
+        }
+
+        input.parse::<Token![|]>()?;
+        let mut params = Punctuated::new();
+        while !input.peek(Token![|]) {
+            params.push_value(input.parse()?);
+            if input.peek(Token![|]) {
+                break;
+            }
+            params.push_punct(input.parse()?);
+        }
+        input.parse::<Token![|]>()?;
+
+        let body = input.parse()?;
+        Ok(Self {
+            attrs,
+            vis,
+            name,
+            generics,
+            fields,
+            params,
+            body,
+        })
+    }
+}
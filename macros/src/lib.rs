@@ -1,14 +1,18 @@
 use proc_macro::TokenStream;
-use syn::DeriveInput;
+use syn::{parse_macro_input, Data, DataUnion, DeriveInput};
 use synstructure::{MacroResult, Structure};
 
+mod asm;
+mod branches;
 mod dump;
+mod instruction_set;
 
 /// Derive macro for the `Dump<'tape>` trait
 ///
-/// This macro *always* derive the trait for the `'tape` lifetime, so be sure
-/// to use the same name `'tape` if your operations contain fields of types such
-/// as `Offset<'tape>`.
+/// This macro derives the trait for the `'tape` lifetime, so be sure to use
+/// the same name `'tape` if your operations contain fields of types such as
+/// `Offset<'tape>`, or to name the lifetime they use with a container
+/// `#[dump(tape = 'a)]` attribute.
 ///
 /// If the type for which the implementation is derived has no type parameters
 /// nor a lifetime parameter named `'tape`, the generated code just delegates
@@ -18,10 +22,30 @@ mod dump;
 /// passing each field to a `field` method on the `Debug*` value just like
 /// `#[derive(Debug)]` does, except that fields whose types include `'tape` or
 /// a type parameter are passed as `&naam::debug_info::Dumper::debug(&self.foo)`
-/// instead of `&self.foo`.
-#[proc_macro_derive(Dump)]
+/// instead of `&self.foo`. The types of those fields are required to
+/// implement `Dump<'tape>` when they include a type parameter, while the
+/// bounds and where clauses of the type itself are kept as is.
+///
+/// Unions are not supported.
+///
+/// The output can be customised with `#[dump(...)]` attributes:
+///
+/// * `#[dump(skip)]` on a field omits it;
+/// * `#[dump(rename = "name")]` on a named field prints it as `name`;
+/// * `#[dump(with = path)]` on a field dumps it by calling `path`, which must
+///   be a function of type `fn(&T, &mut Formatter, Dumper<'tape>) -> Result`;
+/// * `#[dump(mnemonic = "jmp")]` on a struct or an enum variant prints it as
+///   compact assembly, the mnemonic followed by the fields separated by
+///   commas, such as `jmp L3`.
+#[proc_macro_derive(Dump, attributes(dump))]
 pub fn dump_derive(tokens: TokenStream) -> TokenStream {
     match syn::parse::<DeriveInput>(tokens) {
+        Ok(DeriveInput {
+            data: Data::Union(DataUnion { union_token, .. }),
+            ..
+        }) => syn::Error::new_spanned(union_token, "`Dump` cannot be derived for unions")
+            .to_compile_error()
+            .into(),
         Ok(p) => match Structure::try_new(&p) {
             Ok(s) => MacroResult::into_stream(dump::derive(s)),
             Err(e) => e.to_compile_error().into(),
@@ -29,3 +53,290 @@ pub fn dump_derive(tokens: TokenStream) -> TokenStream {
         Err(e) => e.to_compile_error().into(),
     }
 }
+
+/// Derive macro for the `Branches<'tape>` trait
+///
+/// Like `#[derive(Dump)]`, this macro derives the trait for the `'tape`
+/// lifetime, unless another one is named with a container
+/// `#[naam(tape = 'a)]` attribute, or with the `#[dump(tape = 'a)]` one of
+/// `#[derive(Dump)]`:
+///
+/// ```
+/// use naam::builder::{Build, Builder};
+/// use naam::cfg::Branches;
+/// use naam::cpu::DirectThreadedLoop;
+/// use naam::debug_info::Dump;
+/// use naam::tape::UnexpectedEndError;
+/// use naam::{Destination, Execute, Offset, Pc, Program, Runner};
+///
+/// #[derive(Branches, Clone, Copy, Dump)]
+/// #[dump(tape = 'a)]
+/// #[naam(falls_through = false)]
+/// struct Loop<'a>(Offset<'a>);
+///
+/// impl<'a> Execute<'a, ()> for Loop<'a> {
+///     fn as_branches(&self) -> Option<&dyn Branches<'a>> {
+///         Some(self)
+///     }
+///
+///     fn execute(pc: Pc<'a, Self>, runner: Runner<'a>, _ram: &mut ()) -> Destination<'a> {
+///         Err(runner.halt())
+///     }
+/// }
+///
+/// struct Code;
+///
+/// impl Build<DirectThreadedLoop> for Code {
+///     type Ram = ();
+///     type Output = ();
+///     type Error = UnexpectedEndError;
+///
+///     fn build<'tape, 'code>(
+///         &'code self,
+///         builder: &mut Builder<'tape, 'code, DirectThreadedLoop, ()>,
+///     ) -> Result<(), Self::Error>
+///     where
+///         'code: 'tape,
+///     {
+///         let start = builder.offset();
+///         builder.emit(Loop(start))
+///     }
+/// }
+///
+/// # #[derive(Branches, Clone, Copy, Dump)]
+/// # #[naam(tape = 'a)]
+/// # #[dump(tape = 'a)]
+/// # struct Jump<'a>(Offset<'a>);
+/// #
+/// # fn _as_branches<'a, 'b>(jump: &'b Jump<'a>) -> &'b dyn Branches<'a> {
+/// #     jump
+/// # }
+/// #
+/// let program = Program::new(DirectThreadedLoop, vec![], &Code).unwrap();
+/// program.run(&mut ()).unwrap();
+/// let loop_op = program.debug_info().instructions().next().unwrap();
+/// assert_eq!(format!("{:?}", loop_op), "Loop(L0)");
+/// assert_eq!(loop_op.falls_through(), Some(false));
+/// ```
+///
+/// The generated code reports every field of type `Offset<'tape>` as a
+/// branch. Other fields can be reported too with `#[naam(branch)]`, as long
+/// as their types implement `Branches<'tape>`, and offset fields can be
+/// ignored with `#[naam(branch = false)]`.
+///
+/// Operations fall through to the next operation on tape unless the type or
+/// the enum variant is annotated with `#[naam(falls_through = false)]`.
+///
+/// The derived implementation is only recorded in the debug info of programs
+/// if the operation returns it from `Execute::as_branches`.
+#[proc_macro_derive(Branches, attributes(naam))]
+pub fn branches_derive(tokens: TokenStream) -> TokenStream {
+    match syn::parse::<DeriveInput>(tokens) {
+        Ok(p) => match Structure::try_new(&p) {
+            Ok(s) => MacroResult::into_stream(branches::derive(s)),
+            Err(e) => e.to_compile_error().into(),
+        },
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// Declares an instruction set and its token-threaded CPU.
+///
+/// The macro takes the declaration of the CPU, with the name of its
+/// instruction set, the type of the RAM and optionally the type of the
+/// output, followed by the definitions of the operations, each with the
+/// parameters and the body of its `execute` method:
+///
+/// ```
+/// use naam::builder::{Build, Builder};
+/// use naam::tape::UnexpectedEndError;
+/// use naam::Program;
+///
+/// naam::instruction_set! {
+///     /// A calculator.
+///     pub cpu Calc(CalcSet) for Vec<usize> -> usize;
+///
+///     /// Pushes a value.
+///     pub op Push(usize) |pc, _runner, ram| {
+///         ram.push(pc.0);
+///         Ok(pc.next())
+///     }
+///
+///     /// Pops two values and pushes their sum.
+///     pub op Add |pc, _runner, ram| {
+///         let sum = ram.pop().unwrap() + ram.pop().unwrap();
+///         ram.push(sum);
+///         Ok(pc.next())
+///     }
+///
+///     #[naam(falls_through = false)]
+///     pub op Return |_pc, runner, ram| {
+///         Err(runner.halt_with(ram.pop().unwrap()))
+///     }
+/// }
+///
+/// struct Sum(usize, usize);
+///
+/// impl Build<Calc> for Sum {
+///     type Ram = Vec<usize>;
+///     type Output = usize;
+///     type Error = UnexpectedEndError;
+///
+///     fn build<'tape, 'code>(
+///         &'code self,
+///         builder: &mut Builder<'tape, 'code, Calc, Vec<usize>, usize>,
+///     ) -> Result<(), Self::Error>
+///     where
+///         'code: 'tape,
+///     {
+///         builder.emit(Push(self.0))?;
+///         builder.emit(Push(self.1))?;
+///         builder.emit(Add)?;
+///         builder.emit(Return)
+///     }
+/// }
+///
+/// let program = Program::new(Calc, vec![], &Sum(2, 3)).unwrap();
+/// assert_eq!(program.run(&mut vec![]).unwrap(), 5);
+/// ```
+///
+/// Each operation becomes a type deriving `Branches`, `Clone`, `Copy`,
+/// `Debug` and `Dump`, so it can be annotated with `#[naam(...)]` and
+/// `#[dump(...)]` attributes, and reporting its successors through
+/// `Execute::as_branches`. Operations can only be generic over lifetimes,
+/// which all outlive `'tape`.
+///
+/// The instruction set includes `Unreachable` and `Nop`, as opcodes 0 and 1,
+/// followed by the operations in the order they are defined. Its jump table
+/// is a constant. The CPU is declared like a unit struct, as both a type
+/// alias for `TokenThreaded<Set>` and a constant of that type.
+#[proc_macro]
+pub fn instruction_set(tokens: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(tokens as instruction_set::InstructionSet);
+    match instruction_set::expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// Implements `Build` for a type from a listing of operations.
+///
+/// The macro takes an `impl Build<Cpu> for Type` block, with the `Ram`,
+/// `Output` and `Error` associated types, of which only `Ram` is required,
+/// followed by the listing. `Output` defaults to `()` and `Error` to
+/// `naam::tape::UnexpectedEndError`.
+///
+/// The listing is a sequence of operations separated by semicolons, each
+/// written as a struct expression, such as `Push(1)`, `Jump { target }` or
+/// `Jump { 0: target }`,
+/// whose operands are expressions that can refer to `self`. Operations can
+/// be preceded by labels, such as `loop:`, and any operand that is just the
+/// name of a label is replaced by its offset, whether the label is defined
+/// before or after the operation. Only one of the labels referred to by an
+/// operation can be defined after it. `let` statements can be interleaved
+/// with the operations:
+///
+/// ```
+/// # use naam::builder::Build;
+/// # use naam::cfg::Branches;
+/// # use naam::cpu::DirectThreadedLoop as Cpu;
+/// # use naam::debug_info::Dump;
+/// # use naam::{Destination, Execute, Offset, Pc, Program, Runner};
+/// #
+/// # struct SayItNTimes<'a>(&'a str);
+/// #
+/// # struct SayItNTimesRam {
+/// #     counter: usize,
+/// # }
+/// #
+/// # #[derive(Branches, Clone, Copy, Debug, Dump)]
+/// # #[naam(falls_through = false)]
+/// # struct Return(usize);
+/// #
+/// # impl<'tape> Execute<'tape, SayItNTimesRam, usize> for Return {
+/// #     fn execute(
+/// #         pc: Pc<'tape, Self>,
+/// #         runner: Runner<'tape>,
+/// #         _ram: &mut SayItNTimesRam,
+/// #     ) -> Destination<'tape, usize> {
+/// #         Err(runner.halt_with(pc.0))
+/// #     }
+/// # }
+/// #
+/// # #[derive(Branches, Clone, Copy, Debug, Dump)]
+/// # struct PrintLn<'code>(&'code str);
+/// #
+/// # impl<'tape, 'code: 'tape> Execute<'tape, SayItNTimesRam, usize> for PrintLn<'code> {
+/// #     fn execute(
+/// #         pc: Pc<'tape, Self>,
+/// #         _runner: Runner<'tape>,
+/// #         _ram: &mut SayItNTimesRam,
+/// #     ) -> Destination<'tape, usize> {
+/// #         println!("{}", pc.0);
+/// #         Ok(pc.next())
+/// #     }
+/// # }
+/// #
+/// # #[derive(Branches, Clone, Copy, Dump)]
+/// # struct JumpNTimes<'tape>(Offset<'tape>);
+/// #
+/// # impl<'tape> Execute<'tape, SayItNTimesRam, usize> for JumpNTimes<'tape> {
+/// #     fn execute(
+/// #         pc: Pc<'tape, Self>,
+/// #         runner: Runner<'tape>,
+/// #         ram: &mut SayItNTimesRam,
+/// #     ) -> Destination<'tape, usize> {
+/// #         Ok(if ram.counter > 0 {
+/// #             ram.counter -= 1;
+/// #             runner.resolve_offset(pc.0)
+/// #         } else {
+/// #             pc.next()
+/// #         })
+/// #     }
+/// # }
+/// #
+/// naam::asm! {
+///     impl<'a> Build<Cpu> for SayItNTimes<'a> {
+///         type Ram = SayItNTimesRam;
+///         type Output = usize;
+///
+///         let s = self.0;
+///         loop: PrintLn(s);
+///         JumpNTimes(loop);
+///         Return(42)
+///     }
+/// }
+/// #
+/// # let program = Program::new(Cpu, vec![], &SayItNTimes("Hello, world!")).unwrap();
+/// # let mut ram = SayItNTimesRam { counter: 2 };
+/// # assert_eq!(program.run(&mut ram).unwrap(), 42);
+/// # assert_eq!(ram.counter, 0);
+/// #
+/// # struct SkipIt<'a>(&'a str);
+/// #
+/// # naam::asm! {
+/// #     impl<'a> Build<Cpu> for SkipIt<'a> {
+/// #         type Ram = SayItNTimesRam;
+/// #         type Output = usize;
+/// #
+/// #         JumpNTimes { 0: end };
+/// #         PrintLn(self.0);
+/// #         end: Return(7)
+/// #     }
+/// # }
+/// #
+/// # let program = Program::new(Cpu, vec![], &SkipIt("Unreachable")).unwrap();
+/// # let mut ram = SayItNTimesRam { counter: 1 };
+/// # assert_eq!(program.run(&mut ram).unwrap(), 7);
+/// ```
+///
+/// Labels are created with `Builder::named_label`, so they are dumped with
+/// their names.
+#[proc_macro]
+pub fn asm(tokens: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(tokens as asm::Asm);
+    match asm::expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
@@ -1,77 +1,496 @@
 //! Building programs.
 
-use crate::cpu::{Dispatch, DispatchToken, GetDispatchToken};
-use crate::debug_info::{DebugInfo, Dump, Dumper};
+use crate::builtins::{Nop, Switch};
+use crate::cpu::{AcceptTrailing, Dispatch, DispatchToken, GetDispatchToken};
+use crate::debug_info::{DebugWriter, Dump, Dumper, Span, SuccessorsFn};
 use crate::id::Id;
 use crate::tape::{AsClearedWriter, UnexpectedEndError, Writer};
-use crate::{Execute, Offset};
+use crate::{Execute, Offset, Trailing};
+use core::cell::Cell;
 use core::fmt;
 use core::marker::PhantomData as marker;
-use core::mem;
+use core::mem::{self, MaybeUninit};
 use core::ptr;
+use core::slice;
 
 pub trait Build<Cpu> {
     type Ram: ?Sized;
+    type Output;
     type Error: From<UnexpectedEndError>;
 
     fn build<'tape, 'code>(
         &'code self,
-        builder: &mut Builder<'tape, 'code, Cpu, Self::Ram>,
+        builder: &mut Builder<'tape, 'code, Cpu, Self::Ram, Self::Output>,
     ) -> Result<(), Self::Error>
     where
         'code: 'tape;
 }
 
+/// An error returned when building a program.
+#[derive(Clone, Copy, Debug)]
+pub enum BuildError<E> {
+    /// The code failed to build, returning the given error.
+    Build(E),
+    /// A label was never bound before the end of the build.
+    UnboundLabel,
+}
+
+impl<E> From<E> for BuildError<E> {
+    #[inline(always)]
+    fn from(error: E) -> Self {
+        BuildError::Build(error)
+    }
+}
+
 /// A program builder. Passed to the closure given to `Machine::program`.
-pub struct Builder<'tape, 'code, Cpu, Ram>
+pub struct Builder<'tape, 'code, Cpu, Ram, Out = ()>
 where
     Ram: ?Sized,
 {
     cpu: Cpu,
     writer: &'tape mut dyn Writer,
-    debug_info: DebugInfo,
+    debug_info: DebugWriter<'tape>,
+    unbound_labels: usize,
+    tape_align: usize,
     #[allow(dead_code)]
     id: Id<'tape>,
-    marker: marker<(&'code (), fn(&mut Ram))>,
+    #[allow(clippy::type_complexity)]
+    marker: marker<(&'code (), fn(&mut Ram) -> Out)>,
 }
 
-impl<'tape, 'code, Cpu, Ram> Builder<'tape, 'code, Cpu, Ram>
+impl<'tape, 'code, Cpu, Ram, Out> Builder<'tape, 'code, Cpu, Ram, Out>
 where
-    Cpu: Dispatch<Ram>,
+    Cpu: Dispatch<Ram, Out>,
     Ram: ?Sized,
 {
     /// Emits an operation, which must be supported by the builder's CPU.
     ///
+    /// If `Op`'s alignment exceeds `usize`'s, the operation is preceded by
+    /// as many `Nop` operations as needed to align it relative to the start
+    /// of the tape.
+    ///
     /// # Panics
     ///
-    /// This method panics if `Op`'s alignment exceeds `usize`'s.
+    /// This method panics if `Op` sets `Execute::TRAILING`, or if its
+    /// alignment exceeds the one guaranteed by the tape, as set by
+    /// `AsClearedWriter::ALIGN`.
     pub fn emit<Op>(&mut self, op: Op) -> Result<(), UnexpectedEndError>
     where
         'code: 'tape,
-        Cpu: GetDispatchToken<'tape, Op, Ram>,
-        Op: Execute<'tape, Ram>,
+        Cpu: GetDispatchToken<'tape, Op, Ram, Out>,
+        Op: Execute<'tape, Ram, Out>,
     {
-        let instruction = Instruction {
-            token: <Cpu as GetDispatchToken<Op, Ram>>::get_dispatch_token(self.cpu),
-            op,
-        };
+        self.write(op).map(|_| ())
+    }
 
-        if mem::align_of::<Instruction<Op>>() != mem::size_of::<usize>() {
-            panic!("instruction is over-aligned");
+    /// Emits an operation jumping to a label, which may not be bound yet.
+    ///
+    /// The operation is built by calling `op` with the label's offset, and
+    /// `target` must return the field of the operation where that offset
+    /// is stored. If the label is not bound yet, that field will be patched
+    /// when it is.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if the reference returned by `target` doesn't
+    /// point inside the operation.
+    pub fn emit_with_label<Op, F, T>(
+        &mut self,
+        label: &Label<'tape>,
+        op: F,
+        target: T,
+    ) -> Result<(), UnexpectedEndError>
+    where
+        'code: 'tape,
+        Cpu: GetDispatchToken<'tape, Op, Ram, Out>,
+        Op: Execute<'tape, Ram, Out>,
+        F: FnOnce(Offset<'tape>) -> Op,
+        T: FnOnce(&mut Op) -> &mut Offset<'tape>,
+    {
+        if let LabelState::Bound(value) = label.state.get() {
+            return self.emit(op(Offset {
+                value,
+                id: Id::default(),
+            }));
         }
 
-        let size_in_words = mem::size_of_val(&instruction) / mem::size_of::<usize>();
-        #[cfg(feature = "alloc")]
-        let offset = self.writer.word_offset();
+        // Until the label is bound, the operation is built with an offset
+        // to the start of the tape, which is always a valid one.
+        let (start, instruction) = self.write(op(Offset {
+            value: 0,
+            id: Id::default(),
+        }))?;
+        let base = instruction as *const Instruction<Op>;
+        let field = target(&mut instruction.op);
+        let word = start + field_word(base, field);
+        field.value = use_label(label, word);
+        Ok(())
+    }
+
+    /// Emits an operation followed on tape by the given values, which it can
+    /// then access through `Pc::trailing`.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `Op` doesn't set `Execute::TRAILING`, if the
+    /// alignment of its trailing values exceeds `usize`'s, or if they are
+    /// zero-sized.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use naam::builder::{Build, Builder};
+    /// use naam::cpu::DirectThreadedLoop;
+    /// use naam::debug_info::Dump;
+    /// use naam::tape::UnexpectedEndError;
+    /// use naam::{Destination, Execute, Pc, Program, Runner, Trailing};
+    ///
+    /// #[derive(Clone, Copy, Debug, Dump)]
+    /// struct Sum;
+    ///
+    /// impl<'tape> Trailing<'tape> for Sum {
+    ///     type Item = u32;
+    /// }
+    ///
+    /// impl<'tape> Execute<'tape, u32, u32> for Sum {
+    ///     const TRAILING: bool = true;
+    ///
+    ///     fn execute(
+    ///         pc: Pc<'tape, Self>,
+    ///         _runner: Runner<'tape>,
+    ///         ram: &mut u32,
+    ///     ) -> Destination<'tape, u32> {
+    ///         *ram += pc.trailing().iter().sum::<u32>();
+    ///         Ok(pc.next())
+    ///     }
+    /// }
+    ///
+    /// #[derive(Clone, Copy, Debug, Dump)]
+    /// struct Done;
+    ///
+    /// impl<'tape> Execute<'tape, u32, u32> for Done {
+    ///     fn execute(
+    ///         _pc: Pc<'tape, Self>,
+    ///         runner: Runner<'tape>,
+    ///         ram: &mut u32,
+    ///     ) -> Destination<'tape, u32> {
+    ///         Err(runner.halt_with(*ram))
+    ///     }
+    /// }
+    ///
+    /// #[derive(Debug)]
+    /// struct Code;
+    ///
+    /// impl Build<DirectThreadedLoop> for Code {
+    ///     type Ram = u32;
+    ///     type Output = u32;
+    ///     type Error = UnexpectedEndError;
+    ///
+    ///     fn build<'tape, 'code>(
+    ///         &'code self,
+    ///         builder: &mut Builder<'tape, 'code, DirectThreadedLoop, u32, u32>,
+    ///     ) -> Result<(), Self::Error>
+    ///     where
+    ///         'code: 'tape,
+    ///     {
+    ///         builder.emit_with_trailing(Sum, &[1, 2, 3])?;
+    ///         builder.emit_with_trailing(Sum, &[])?;
+    ///         builder.emit_with_trailing(Sum, &[10, 20, 30, 40, 50])?;
+    ///         builder.emit(Done)
+    ///     }
+    /// }
+    ///
+    /// let program = Program::new(DirectThreadedLoop, vec![], &Code).unwrap();
+    /// assert_eq!(program.run(&mut 0).unwrap(), 156);
+    /// let ops = program
+    ///     .debug_info()
+    ///     .instructions()
+    ///     .map(|instruction| format!("{:?}", instruction))
+    ///     .collect::<Vec<_>>();
+    /// assert_eq!(
+    ///     ops,
+    ///     [
+    ///         "Sum [1, 2, 3]",
+    ///         "Sum []",
+    ///         "Sum [10, 20, 30, 40, 50]",
+    ///         "Done",
+    ///         "Unreachable",
+    ///     ],
+    /// );
+    /// ```
+    pub fn emit_with_trailing<Op>(
+        &mut self,
+        op: Op,
+        trailing: &[Op::Item],
+    ) -> Result<(), UnexpectedEndError>
+    where
+        'code: 'tape,
+        Cpu: GetDispatchToken<'tape, Op, Ram, Out> + AcceptTrailing<Op::Item>,
+        Op: Execute<'tape, Ram, Out> + Trailing<'tape>,
+    {
+        let (_, _, words) = self.write_trailing(op, trailing.len())?;
         unsafe {
-            let slice = self.writer.take(size_in_words)?;
-            ptr::write(slice.as_mut_ptr() as *mut _, instruction);
-            #[cfg(feature = "alloc")]
-            self.debug_info.push::<Instruction<Op>>(offset);
+            ptr::copy_nonoverlapping(
+                trailing.as_ptr(),
+                words.as_mut_ptr() as *mut Op::Item,
+                trailing.len(),
+            );
         }
         Ok(())
     }
 
+    /// Emits a `Switch` operation, followed on tape by its jump table.
+    ///
+    /// The operation continues with the label at the index computed by
+    /// `index`, or with `default` if that index is out of bounds. Labels
+    /// may not be bound yet, in which case they are patched when they are,
+    /// and the same label may be used by several entries and the default.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use naam::builder::{Build, Builder};
+    /// use naam::builtins::Selector;
+    /// use naam::cpu::DirectThreadedLoop;
+    /// use naam::debug_info::Dump;
+    /// use naam::tape::UnexpectedEndError;
+    /// use naam::{Destination, Execute, Pc, Program, Runner};
+    ///
+    /// #[derive(Clone, Copy, Debug, Dump)]
+    /// struct Input;
+    ///
+    /// impl Selector<usize> for Input {
+    ///     fn select(&self, ram: &mut usize) -> usize {
+    ///         *ram
+    ///     }
+    /// }
+    ///
+    /// #[derive(Clone, Copy, Debug, Dump)]
+    /// struct Return(&'static str);
+    ///
+    /// impl<'tape> Execute<'tape, usize, &'static str> for Return {
+    ///     fn execute(
+    ///         pc: Pc<'tape, Self>,
+    ///         runner: Runner<'tape>,
+    ///         _ram: &mut usize,
+    ///     ) -> Destination<'tape, &'static str> {
+    ///         Err(runner.halt_with(pc.0))
+    ///     }
+    /// }
+    ///
+    /// #[derive(Debug)]
+    /// struct Parity;
+    ///
+    /// impl Build<DirectThreadedLoop> for Parity {
+    ///     type Ram = usize;
+    ///     type Output = &'static str;
+    ///     type Error = UnexpectedEndError;
+    ///
+    ///     fn build<'tape, 'code>(
+    ///         &'code self,
+    ///         builder: &mut Builder<'tape, 'code, DirectThreadedLoop, usize, &'static str>,
+    ///     ) -> Result<(), Self::Error>
+    ///     where
+    ///         'code: 'tape,
+    ///     {
+    ///         let mut even = builder.label();
+    ///         let mut odd = builder.label();
+    ///         let mut other = builder.named_label("other");
+    ///         builder.emit_switch(Input, &other, &[&even, &odd, &even, &odd])?;
+    ///         builder.bind(&mut even);
+    ///         builder.emit(Return("even"))?;
+    ///         builder.bind(&mut odd);
+    ///         builder.emit(Return("odd"))?;
+    ///         builder.bind(&mut other);
+    ///         builder.emit(Return("other"))
+    ///     }
+    /// }
+    ///
+    /// let program = Program::new(DirectThreadedLoop, vec![], &Parity).unwrap();
+    /// for (input, output) in [(0, "even"), (1, "odd"), (2, "even"), (3, "odd"), (4, "other")] {
+    ///     assert_eq!(program.run(&mut { input }).unwrap(), output);
+    /// }
+    /// let switch = program.debug_info().instructions().next().unwrap();
+    /// assert_eq!(
+    ///     format!("{:?}", switch),
+    ///     "Switch { default: other, index: Input } [L0, L1, L0, L1]",
+    /// );
+    /// ```
+    pub fn emit_switch<Index>(
+        &mut self,
+        index: Index,
+        default: &Label<'tape>,
+        targets: &[&Label<'tape>],
+    ) -> Result<(), UnexpectedEndError>
+    where
+        'code: 'tape,
+        Cpu:
+            GetDispatchToken<'tape, Switch<'tape, Index>, Ram, Out> + AcceptTrailing<Offset<'tape>>,
+        Switch<'tape, Index>: Execute<'tape, Ram, Out>,
+    {
+        let op = Switch {
+            default: Offset {
+                value: 0,
+                id: Id::default(),
+            },
+            index,
+        };
+        let (start, instruction, table) = self.write_trailing(op, targets.len())?;
+        let base = instruction as *const Instruction<Switch<'tape, Index>>;
+        let field = &mut instruction.op.default;
+        let word = start + field_word(base, field);
+        field.value = use_label(default, word);
+        // The jump table starts after the instruction and the word storing
+        // the size of the trailing data.
+        let table_start = start + mem::size_of::<Instruction<Switch<'tape, Index>>>() / WORD + 1;
+        for (i, (entry, label)) in table.iter_mut().zip(targets).enumerate() {
+            *entry = MaybeUninit::new(use_label(label, table_start + i));
+        }
+        Ok(())
+    }
+
+    /// Returns a new unbound label.
+    ///
+    /// All labels must be bound before the end of the build, otherwise
+    /// `Program::new` returns `BuildError::UnboundLabel`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use naam::builder::{Build, BuildError, Builder};
+    /// use naam::builtins::Jump;
+    /// use naam::cpu::DirectThreadedLoop;
+    /// use naam::debug_info::Dump;
+    /// use naam::tape::UnexpectedEndError;
+    /// use naam::{Destination, Execute, Pc, Program, Runner};
+    ///
+    /// #[derive(Clone, Copy, Debug, Dump)]
+    /// struct Return(usize);
+    ///
+    /// impl<'tape> Execute<'tape, (), usize> for Return {
+    ///     fn execute(
+    ///         pc: Pc<'tape, Self>,
+    ///         runner: Runner<'tape>,
+    ///         _ram: &mut (),
+    ///     ) -> Destination<'tape, usize> {
+    ///         Err(runner.halt_with(pc.0))
+    ///     }
+    /// }
+    ///
+    /// #[derive(Debug)]
+    /// struct Skip {
+    ///     bind: bool,
+    /// }
+    ///
+    /// impl Build<DirectThreadedLoop> for Skip {
+    ///     type Ram = ();
+    ///     type Output = usize;
+    ///     type Error = UnexpectedEndError;
+    ///
+    ///     fn build<'tape, 'code>(
+    ///         &'code self,
+    ///         builder: &mut Builder<'tape, 'code, DirectThreadedLoop, (), usize>,
+    ///     ) -> Result<(), Self::Error>
+    ///     where
+    ///         'code: 'tape,
+    ///     {
+    ///         let mut end = builder.label();
+    ///         builder.emit_with_label(&end, Jump, |jump| &mut jump.0)?;
+    ///         builder.emit_with_label(&end, Jump, |jump| &mut jump.0)?;
+    ///         builder.emit(Return(1))?;
+    ///         if self.bind {
+    ///             builder.bind(&mut end);
+    ///         }
+    ///         builder.emit(Return(0))
+    ///     }
+    /// }
+    ///
+    /// let program = Program::new(DirectThreadedLoop, vec![], &Skip { bind: true }).unwrap();
+    /// assert_eq!(program.run(&mut ()).unwrap(), 0);
+    /// let ops = program
+    ///     .debug_info()
+    ///     .instructions()
+    ///     .map(|instruction| format!("{:?}", instruction))
+    ///     .collect::<Vec<_>>();
+    /// assert_eq!(ops, ["Jump(L0)", "Jump(L0)", "Return(1)", "Return(0)", "Unreachable"]);
+    ///
+    /// let error = Program::new(DirectThreadedLoop, vec![], &Skip { bind: false }).unwrap_err();
+    /// assert!(matches!(error, BuildError::UnboundLabel));
+    /// ```
+    #[inline(always)]
+    pub fn label(&mut self) -> Label<'tape> {
+        self.unbound_labels += 1;
+        Label {
+            state: Cell::new(LabelState::Unbound { last_use: None }),
+            name: None,
+            id: Id::default(),
+        }
+    }
+
+    /// Returns a new unbound label with the given name, which is used
+    /// instead of `L0`, `L1` and so on when dumping the program.
+    ///
+    /// See `Builder::label`.
+    #[inline(always)]
+    pub fn named_label(&mut self, name: &'code str) -> Label<'tape>
+    where
+        'code: 'tape,
+    {
+        Label {
+            name: Some(name),
+            ..self.label()
+        }
+    }
+
+    /// Binds a label to the current offset in the tape, patching all the
+    /// operations that were emitted with it so far.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if the label is already bound.
+    pub fn bind(&mut self, label: &mut Label<'tape>) {
+        let mut next = match label.state.get() {
+            LabelState::Bound(_) => panic!("label is already bound"),
+            LabelState::Unbound { last_use } => last_use,
+        };
+        let value = self.offset().value;
+        let words = self.writer.written_mut();
+        while let Some(word) = next {
+            let link = unsafe { words[word].assume_init() };
+            next = if link == usize::MAX { None } else { Some(link) };
+            words[word] = MaybeUninit::new(value);
+        }
+        label.state.set(LabelState::Bound(value));
+        self.unbound_labels -= 1;
+        self.debug_info.bind(value / WORD, label.name);
+    }
+
+    /// Sets the source span of the operations emitted from now on, until
+    /// it is set again.
+    ///
+    /// The span is recorded in the debug info of the program, and printed
+    /// alongside each operation when the program is dumped.
+    #[inline(always)]
+    pub fn set_span(&mut self, span: Option<Span<'code>>)
+    where
+        'code: 'tape,
+    {
+        self.debug_info.set_span(span);
+    }
+
+    /// Sets the symbol, such as a function name, of the operations emitted
+    /// from now on, until it is set again.
+    ///
+    /// The symbol is recorded in the debug info of the program, and printed
+    /// alongside each operation when the program is dumped.
+    #[inline(always)]
+    pub fn set_symbol(&mut self, symbol: Option<&'code str>)
+    where
+        'code: 'tape,
+    {
+        self.debug_info.set_symbol(symbol);
+    }
+
     /// Returns the current offset in the tape.
     ///
     /// The current offset is the distance between the beginning of the tape
@@ -79,34 +498,238 @@ where
     #[inline(always)]
     pub fn offset(&self) -> Offset<'tape> {
         Offset {
-            value: self
-                .writer
-                .word_offset()
-                .wrapping_mul(mem::size_of::<usize>()),
+            value: self.writer.word_offset().wrapping_mul(WORD),
             id: Id::default(),
         }
     }
 
     #[inline(always)]
-    pub(crate) fn new<Tape>(cpu: Cpu, tape: &'tape mut Tape) -> Self
+    pub(crate) fn new<Tape, DebugTape>(
+        cpu: Cpu,
+        tape: &'tape mut Tape,
+        debug_tape: &'tape mut DebugTape,
+    ) -> Self
     where
         Tape: AsClearedWriter,
+        DebugTape: AsClearedWriter,
     {
         Self {
             writer: tape.as_cleared_writer(),
             cpu,
-            debug_info: DebugInfo::default(),
+            debug_info: DebugWriter::new(debug_tape.as_cleared_writer()),
+            unbound_labels: 0,
+            tape_align: Tape::ALIGN,
             id: Id::default(),
             marker,
         }
     }
 
+    /// Finishes the build, labelling branch targets in the debug info, and
+    /// returns whether the debug info is complete.
+    ///
+    /// This method fails if a label was never bound.
+    pub(crate) fn finish<E>(mut self) -> Result<bool, BuildError<E>> {
+        if self.unbound_labels != 0 {
+            return Err(BuildError::UnboundLabel);
+        }
+        // All the offsets of the operations were resolved, so their branches
+        // can be read.
+        unsafe { self.debug_info.finish(self.writer.written_mut()) };
+        Ok(self.debug_info.is_complete())
+    }
+
+    /// Writes an instruction, returning its offset in words.
+    fn write<Op>(&mut self, op: Op) -> Result<(usize, &mut Instruction<Op>), UnexpectedEndError>
+    where
+        'code: 'tape,
+        Cpu: GetDispatchToken<'tape, Op, Ram, Out>,
+        Op: Execute<'tape, Ram, Out>,
+    {
+        if Op::TRAILING {
+            panic!("operation must be emitted with trailing data");
+        }
+        self.write_words::<Op, Instruction<Op>>(op, 0, successors::<Op, Ram, Out>)
+            .map(|(offset, instruction, _)| (offset, instruction))
+    }
+
+    /// Writes an instruction followed by room for `len` trailing values,
+    /// which are left for the caller to initialise, returning its offset in
+    /// words.
+    ///
+    /// The trailing values are preceded by a word storing their size in
+    /// bytes, so that `Pc::next` can skip past them.
+    fn write_trailing<Op>(
+        &mut self,
+        op: Op,
+        len: usize,
+    ) -> Result<Written<'_, Op>, UnexpectedEndError>
+    where
+        'code: 'tape,
+        Cpu: GetDispatchToken<'tape, Op, Ram, Out> + AcceptTrailing<Op::Item>,
+        Op: Execute<'tape, Ram, Out> + Trailing<'tape>,
+    {
+        if !Op::TRAILING {
+            panic!("operation doesn't declare trailing data");
+        }
+        if mem::align_of::<Op::Item>() > WORD {
+            panic!("trailing data is over-aligned");
+        }
+        if mem::size_of::<Op::Item>() == 0 {
+            panic!("trailing data is zero-sized");
+        }
+
+        let bytes = len
+            .checked_mul(mem::size_of::<Op::Item>())
+            .ok_or(UnexpectedEndError)?;
+        let words = words_for(bytes);
+        let (offset, instruction, trailing) = self.write_words::<Op, TrailingInstruction<Op>>(
+            op,
+            1 + words,
+            trailing_successors::<Op, Ram, Out>,
+        )?;
+        trailing[0] = MaybeUninit::new(bytes);
+        Ok((offset, instruction, &mut trailing[1..]))
+    }
+
+    /// Writes an instruction followed by the given number of words, which
+    /// are left for the caller to initialise, recording it in the debug info
+    /// to be dumped as an `I`, with the given successors, and returning its
+    /// offset in words.
+    fn write_words<Op, I>(
+        &mut self,
+        op: Op,
+        trailing_words: usize,
+        successors: SuccessorsFn<'tape>,
+    ) -> Result<Written<'_, Op>, UnexpectedEndError>
+    where
+        'code: 'tape,
+        Cpu: GetDispatchToken<'tape, Op, Ram, Out>,
+        Op: Execute<'tape, Ram, Out>,
+        I: Dump<'tape>,
+    {
+        let instruction = Instruction {
+            token: <Cpu as GetDispatchToken<Op, Ram, Out>>::get_dispatch_token(self.cpu),
+            op,
+        };
+
+        let align = mem::align_of::<Instruction<Op>>();
+        let size_in_words = mem::size_of_val(&instruction) / WORD;
+        let words = size_in_words.saturating_add(trailing_words);
+        let offset = if align > WORD {
+            self.take_aligned(align, words)?
+        } else {
+            let offset = self.writer.word_offset();
+            self.writer.take(words)?;
+            offset
+        };
+        unsafe {
+            let slice = &mut self.writer.written_mut()[offset..];
+            let (head, trailing) = slice.split_at_mut(size_in_words);
+            let ptr = head.as_mut_ptr() as *mut Instruction<Op>;
+            ptr::write(ptr, instruction);
+            self.debug_info.push::<I>(offset, words, successors);
+            Ok((offset, &mut *ptr, trailing))
+        }
+    }
+
+    /// Takes the given number of words from the tape, starting at an offset
+    /// aligned to `align`, returning their offset in words.
+    ///
+    /// The words skipped to align them are filled with `Nop` operations.
+    fn take_aligned(&mut self, align: usize, words: usize) -> Result<usize, UnexpectedEndError> {
+        if align > self.tape_align {
+            panic!("operation is over-aligned for this tape");
+        }
+        let nop = <Cpu as GetDispatchToken<Nop, Ram, Out>>::get_dispatch_token(self.cpu);
+        while self.writer.word_offset() & (align / WORD - 1) != 0 {
+            let offset = self.writer.word_offset();
+            self.writer.take(1)?[0] = MaybeUninit::new(usize::from(nop));
+            unsafe {
+                self.debug_info
+                    .push::<Instruction<Nop>>(offset, 1, successors::<Nop, Ram, Out>)
+            };
+        }
+        let offset = self.writer.word_offset();
+        self.writer.take(words)?;
+        Ok(offset)
+    }
+}
+
+const WORD: usize = mem::size_of::<usize>();
+
+/// An instruction that was just written, with its offset in words and the
+/// words following it.
+type Written<'a, Op> = (usize, &'a mut Instruction<Op>, &'a mut [MaybeUninit<usize>]);
+
+/// Returns the number of words needed to store the given number of bytes.
+#[inline(always)]
+fn words_for(bytes: usize) -> usize {
+    bytes / WORD + (bytes & (WORD - 1) != 0) as usize
+}
+
+/// Returns the offset in words of an offset field within an instruction.
+///
+/// # Panics
+///
+/// This function panics if the field doesn't point inside the instruction.
+fn field_word<Op>(instruction: *const Instruction<Op>, field: &Offset) -> usize {
+    let base = instruction as usize;
+    let field_offset = (field as *const Offset as usize).wrapping_sub(base);
+    if field_offset >= mem::size_of::<Instruction<Op>>() {
+        panic!("label target is not a field of the operation");
+    }
+    field_offset / WORD
+}
+
+/// Returns the value to store in the offset at the given word to refer to
+/// a label.
+///
+/// Unbound uses of a label form a linked list threaded through the offsets
+/// to patch, `usize::MAX` marking its end.
+fn use_label(label: &Label, word: usize) -> usize {
+    match label.state.get() {
+        LabelState::Bound(value) => value,
+        LabelState::Unbound { last_use } => {
+            label.state.set(LabelState::Unbound {
+                last_use: Some(word),
+            });
+            last_use.unwrap_or(usize::MAX)
+        }
+    }
+}
+
+/// A label, standing for an offset in the tape that may not be known yet.
+///
+/// Labels are created by `Builder::label` and bound by `Builder::bind`. They
+/// can be used through shared references, so that a single label can appear
+/// several times in a `Switch` jump table.
+pub struct Label<'tape> {
+    state: Cell<LabelState>,
+    name: Option<&'tape str>,
+    #[allow(dead_code)]
+    id: Id<'tape>,
+}
+
+impl<'tape> Label<'tape> {
+    /// Returns the offset this label is bound to, if any.
     #[inline(always)]
-    pub(crate) unsafe fn into_debug_info(self) -> DebugInfo {
-        self.debug_info
+    pub fn offset(&self) -> Option<Offset<'tape>> {
+        match self.state.get() {
+            LabelState::Bound(value) => Some(Offset {
+                value,
+                id: Id::default(),
+            }),
+            LabelState::Unbound { .. } => None,
+        }
     }
 }
 
+#[derive(Clone, Copy)]
+enum LabelState {
+    Unbound { last_use: Option<usize> },
+    Bound(usize),
+}
+
 #[derive(Clone, Copy)]
 #[repr(C)]
 pub(crate) struct Instruction<Op> {
@@ -114,13 +737,104 @@ pub(crate) struct Instruction<Op> {
     pub(crate) op: Op,
 }
 
+impl<Op> Instruction<Op> {
+    /// Returns the end of the instruction, where the next one starts unless
+    /// the instruction has trailing data.
+    #[inline(always)]
+    pub(crate) fn end(&self) -> *const DispatchToken {
+        (self as *const Self).wrapping_add(1) as *const DispatchToken
+    }
+
+    /// Returns the end of the trailing data of the instruction, where the
+    /// next one starts.
+    ///
+    /// # Safety
+    ///
+    /// The instruction must have been written with trailing data.
+    #[inline(always)]
+    pub(crate) unsafe fn end_of_trailing(&self) -> *const DispatchToken {
+        let header = self.end() as *const usize;
+        header.add(1 + words_for(*header)) as *const DispatchToken
+    }
+
+    /// Returns the trailing data of the instruction.
+    ///
+    /// # Safety
+    ///
+    /// The instruction must have been written with trailing data.
+    #[inline(always)]
+    pub(crate) unsafe fn trailing<'tape>(&self) -> &[Op::Item]
+    where
+        Op: Trailing<'tape>,
+    {
+        let header = self.end() as *const usize;
+        slice::from_raw_parts(
+            header.add(1) as *const Op::Item,
+            *header / mem::size_of::<Op::Item>(),
+        )
+    }
+}
+
 impl<'tape, Op> Dump<'tape> for Instruction<Op>
 where
     Op: Dump<'tape>,
 {
     fn dump(&self, fmt: &mut fmt::Formatter, dumper: Dumper<'tape>) -> fmt::Result {
-        fmt::Pointer::fmt(&self, fmt)?;
-        fmt.write_str(": ")?;
         self.op.dump(fmt, dumper)
     }
 }
+
+/// Reports the successors of an instruction, if its operation reports them
+/// through `Execute::as_branches`.
+unsafe fn successors<'tape, Op, Ram, Out>(
+    ptr: *const MaybeUninit<usize>,
+    branch: &mut dyn FnMut(Offset<'tape>),
+) -> Option<bool>
+where
+    Op: Execute<'tape, Ram, Out>,
+    Ram: ?Sized,
+{
+    let instruction = &*(ptr as *const Instruction<Op>);
+    let branches = instruction.op.as_branches()?;
+    branches.branches(branch);
+    Some(branches.falls_through())
+}
+
+/// Reports the successors of an instruction written with trailing data,
+/// including the offsets held by the trailing values.
+unsafe fn trailing_successors<'tape, Op, Ram, Out>(
+    ptr: *const MaybeUninit<usize>,
+    branch: &mut dyn FnMut(Offset<'tape>),
+) -> Option<bool>
+where
+    Op: Execute<'tape, Ram, Out> + Trailing<'tape>,
+    Ram: ?Sized,
+{
+    let falls_through = successors::<Op, Ram, Out>(ptr, branch)?;
+    let instruction = &*(ptr as *const Instruction<Op>);
+    for item in instruction.trailing() {
+        if let Some(branches) = Op::item_as_branches(item) {
+            branches.branches(branch);
+        }
+    }
+    Some(falls_through)
+}
+
+/// An instruction written with trailing data, as recorded in the debug info.
+#[repr(transparent)]
+struct TrailingInstruction<Op>(Instruction<Op>);
+
+impl<'tape, Op> Dump<'tape> for TrailingInstruction<Op>
+where
+    Op: Trailing<'tape> + Dump<'tape>,
+{
+    fn dump(&self, fmt: &mut fmt::Formatter, dumper: Dumper<'tape>) -> fmt::Result {
+        self.0.dump(fmt, dumper)?;
+        fmt.write_str(" ")?;
+        // This is only ever dumped from the tape it was written to.
+        let trailing = unsafe { self.0.trailing() };
+        fmt.debug_list()
+            .entries(trailing.iter().map(|item| dumper.debug(item)))
+            .finish()
+    }
+}
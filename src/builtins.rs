@@ -1,36 +1,438 @@
 //! Built-in operations.
 
+use crate::cfg::Branches;
 use crate::debug_info::Dump;
-use crate::{Destination, Execute, Pc, Runner};
+use crate::id::BuildId;
+use crate::{Destination, Execute, Offset, Pc, Runner, Trailing};
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem::MaybeUninit;
 
 // Hack so that #[derive(Dump)] works in naam itself.
 use crate as naam;
 
 /// The classic “nop” operation, which does nothing and just continues with
 /// the next operation on tape.
-#[derive(Clone, Copy, Debug, Dump)]
+#[derive(Branches, Clone, Copy, Debug, Dump)]
 pub struct Nop;
 
-impl<'tape, Ram> Execute<'tape, Ram> for Nop
+impl<'tape, Ram, Out> Execute<'tape, Ram, Out> for Nop
 where
     Ram: ?Sized,
 {
     #[inline(always)]
-    fn execute(pc: Pc<'tape, Self>, _runner: Runner<'tape>, _ram: &mut Ram) -> Destination<'tape> {
+    fn as_branches(&self) -> Option<&dyn Branches<'tape>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn execute(
+        pc: Pc<'tape, Self>,
+        _runner: Runner<'tape>,
+        _ram: &mut Ram,
+    ) -> Destination<'tape, Out> {
         Ok(pc.next())
     }
 }
 
 /// The unreachable operation, which always panic.
-#[derive(Clone, Copy, Debug, Dump)]
+#[derive(Branches, Clone, Copy, Debug, Dump)]
+#[naam(falls_through = false)]
 pub struct Unreachable;
 
-impl<'tape, Ram> Execute<'tape, Ram> for Unreachable
+impl<'tape, Ram, Out> Execute<'tape, Ram, Out> for Unreachable
 where
     Ram: ?Sized,
 {
     #[inline(always)]
-    fn execute(_pc: Pc<'tape, Self>, _runner: Runner<'tape>, _ram: &mut Ram) -> Destination<'tape> {
+    fn as_branches(&self) -> Option<&dyn Branches<'tape>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn execute(
+        _pc: Pc<'tape, Self>,
+        _runner: Runner<'tape>,
+        _ram: &mut Ram,
+    ) -> Destination<'tape, Out> {
         panic!("reached unreachable tape")
     }
 }
+
+/// The unconditional jump operation, which continues with the operation at
+/// the given offset.
+#[derive(Branches, Clone, Copy, Debug, Dump)]
+#[naam(falls_through = false)]
+pub struct Jump<'tape>(pub Offset<'tape>);
+
+impl<'tape, Ram, Out> Execute<'tape, Ram, Out> for Jump<'tape>
+where
+    Ram: ?Sized,
+{
+    #[inline(always)]
+    fn as_branches(&self) -> Option<&dyn Branches<'tape>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn execute(
+        pc: Pc<'tape, Self>,
+        runner: Runner<'tape>,
+        _ram: &mut Ram,
+    ) -> Destination<'tape, Out> {
+        Ok(runner.resolve_offset(pc.0))
+    }
+}
+
+/// The conditional jump operation, which continues with the operation at
+/// the given offset if the condition holds, and with the next operation
+/// otherwise.
+#[derive(Branches, Clone, Copy, Debug, Dump)]
+pub struct JumpIf<'tape, Cond>(pub Offset<'tape>, pub Cond);
+
+impl<'tape, Ram, Out, Cond> Execute<'tape, Ram, Out> for JumpIf<'tape, Cond>
+where
+    Ram: ?Sized,
+    Cond: 'tape + Condition<Ram> + Copy + Dump<'tape>,
+{
+    #[inline(always)]
+    fn as_branches(&self) -> Option<&dyn Branches<'tape>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn execute(
+        pc: Pc<'tape, Self>,
+        runner: Runner<'tape>,
+        ram: &mut Ram,
+    ) -> Destination<'tape, Out> {
+        Ok(if pc.1.check(ram) {
+            runner.resolve_offset(pc.0)
+        } else {
+            pc.next()
+        })
+    }
+}
+
+/// The inverted conditional jump operation, which continues with the
+/// operation at the given offset if the condition doesn't hold, and with the
+/// next operation otherwise.
+#[derive(Branches, Clone, Copy, Debug, Dump)]
+pub struct JumpUnless<'tape, Cond>(pub Offset<'tape>, pub Cond);
+
+impl<'tape, Ram, Out, Cond> Execute<'tape, Ram, Out> for JumpUnless<'tape, Cond>
+where
+    Ram: ?Sized,
+    Cond: 'tape + Condition<Ram> + Copy + Dump<'tape>,
+{
+    #[inline(always)]
+    fn as_branches(&self) -> Option<&dyn Branches<'tape>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn execute(
+        pc: Pc<'tape, Self>,
+        runner: Runner<'tape>,
+        ram: &mut Ram,
+    ) -> Destination<'tape, Out> {
+        Ok(if pc.1.check(ram) {
+            pc.next()
+        } else {
+            runner.resolve_offset(pc.0)
+        })
+    }
+}
+
+/// A predicate over RAM, used by `JumpIf` and `JumpUnless`.
+pub trait Condition<Ram>
+where
+    Ram: ?Sized,
+{
+    /// Returns whether the condition holds.
+    fn check(&self, ram: &mut Ram) -> bool;
+}
+
+/// The multi-way jump operation, which continues with the operation at the
+/// offset found at the selected index of its jump table, and with the
+/// operation at the default offset if that index is out of bounds.
+///
+/// The jump table is the trailing data of the operation, and is usually
+/// emitted with `Builder::emit_switch` so that its entries can refer to
+/// labels.
+// The entries of the jump table are reported as branches through the
+// trailing data.
+#[derive(Branches, Clone, Copy, Debug, Dump)]
+#[naam(falls_through = false)]
+pub struct Switch<'tape, Index> {
+    /// The offset to jump to when the index is out of bounds.
+    pub default: Offset<'tape>,
+    /// The index computed to select an entry in the jump table.
+    pub index: Index,
+}
+
+impl<'tape, Index> Trailing<'tape> for Switch<'tape, Index>
+where
+    Index: 'tape,
+{
+    type Item = Offset<'tape>;
+
+    #[inline(always)]
+    fn item_as_branches(item: &Self::Item) -> Option<&dyn Branches<'tape>> {
+        Some(item)
+    }
+}
+
+impl<'tape, Ram, Out, Index> Execute<'tape, Ram, Out> for Switch<'tape, Index>
+where
+    Ram: ?Sized,
+    Index: 'tape + Selector<Ram> + Copy + Dump<'tape>,
+{
+    const TRAILING: bool = true;
+
+    #[inline(always)]
+    fn as_branches(&self) -> Option<&dyn Branches<'tape>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn execute(
+        pc: Pc<'tape, Self>,
+        runner: Runner<'tape>,
+        ram: &mut Ram,
+    ) -> Destination<'tape, Out> {
+        let index = pc.index.select(ram);
+        let target = pc.trailing().get(index).copied().unwrap_or(pc.default);
+        Ok(runner.resolve_offset(target))
+    }
+}
+
+/// A computation of an index from RAM, used by `Switch`.
+pub trait Selector<Ram>
+where
+    Ram: ?Sized,
+{
+    /// Returns the selected index.
+    fn select(&self, ram: &mut Ram) -> usize;
+}
+
+/// The call operation, which pushes the address of the next operation on
+/// the return stack and continues with the operation at the given offset.
+///
+/// If the return stack is full, the program halts with
+/// `ReturnStackError::Overflow`. The output of the program must thus
+/// implement `From<ReturnStackError>`, which rules out `()`.
+// The next operation is where `Ret` continues, so calls fall through.
+#[derive(Branches, Clone, Copy, Debug, Dump)]
+pub struct Call<'tape>(pub Offset<'tape>);
+
+impl<'tape, Ram, Out> Execute<'tape, Ram, Out> for Call<'tape>
+where
+    Ram: ReturnStack + ?Sized,
+    Out: From<ReturnStackError>,
+{
+    #[inline(always)]
+    fn as_branches(&self) -> Option<&dyn Branches<'tape>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn execute(
+        pc: Pc<'tape, Self>,
+        runner: Runner<'tape>,
+        ram: &mut Ram,
+    ) -> Destination<'tape, Out> {
+        let addr = ReturnAddr {
+            offset: runner.offset_of(pc.next()).value,
+            build: runner.build,
+        };
+        match ram.push_return(addr) {
+            Ok(()) => Ok(runner.resolve_offset(pc.0)),
+            Err(error) => Err(runner.halt_with(error.into())),
+        }
+    }
+}
+
+/// The return operation, which pops an address from the return stack and
+/// continues with the operation there.
+///
+/// If the return stack is empty, the program halts with
+/// `ReturnStackError::Underflow`. As with `Call`, the output of the program
+/// must implement `From<ReturnStackError>`.
+///
+/// # Panics
+///
+/// This operation panics if the return address was pushed by another
+/// program, for example one run with the same RAM before.
+// Return addresses are only known at run time.
+#[derive(Branches, Clone, Copy, Debug, Dump)]
+#[naam(falls_through = false)]
+pub struct Ret;
+
+impl<'tape, Ram, Out> Execute<'tape, Ram, Out> for Ret
+where
+    Ram: ReturnStack + ?Sized,
+    Out: From<ReturnStackError>,
+{
+    #[inline(always)]
+    fn as_branches(&self) -> Option<&dyn Branches<'tape>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn execute(
+        _pc: Pc<'tape, Self>,
+        runner: Runner<'tape>,
+        ram: &mut Ram,
+    ) -> Destination<'tape, Out> {
+        let addr = match ram.pop_return() {
+            Some(addr) => addr,
+            None => return Err(runner.halt_with(ReturnStackError::Underflow.into())),
+        };
+        // The offset is only known to be the start of an operation in the
+        // program that pushed it, which can't share its build id with any
+        // other program.
+        if addr.build != runner.build {
+            panic!("return address comes from another program");
+        }
+        Ok(runner.resolve_offset(Offset {
+            value: addr.offset,
+            id: runner.id,
+        }))
+    }
+}
+
+/// RAM providing a return stack, used by `Call` and `Ret`.
+pub trait ReturnStack {
+    /// Pushes a return address on the stack.
+    fn push_return(&mut self, addr: ReturnAddr) -> Result<(), ReturnStackError>;
+
+    /// Pops a return address from the stack, if it isn't empty.
+    fn pop_return(&mut self) -> Option<ReturnAddr>;
+}
+
+#[cfg(feature = "alloc")]
+impl ReturnStack for Vec<ReturnAddr> {
+    #[inline(always)]
+    fn push_return(&mut self, addr: ReturnAddr) -> Result<(), ReturnStackError> {
+        self.push(addr);
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn pop_return(&mut self) -> Option<ReturnAddr> {
+        self.pop()
+    }
+}
+
+/// A return stack of fixed capacity, for RAM that can't allocate.
+///
+/// `Addrs` is typically either `&mut [MaybeUninit<ReturnAddr>]` or
+/// `[MaybeUninit<ReturnAddr>; N]`. Pushing more addresses than fit in it
+/// returns `ReturnStackError::Overflow`.
+pub struct FixedReturnStack<Addrs> {
+    addrs: Addrs,
+    len: usize,
+}
+
+impl<Addrs> FixedReturnStack<Addrs>
+where
+    Addrs: AsRef<[MaybeUninit<ReturnAddr>]> + AsMut<[MaybeUninit<ReturnAddr>]>,
+{
+    /// Returns a new empty return stack, backed by the given addresses.
+    #[inline(always)]
+    pub fn new(addrs: Addrs) -> Self {
+        Self { addrs, len: 0 }
+    }
+
+    /// Returns the maximum number of addresses that can be pushed on the
+    /// stack.
+    #[inline(always)]
+    pub fn capacity(&self) -> usize {
+        self.addrs.as_ref().len()
+    }
+
+    /// Returns the number of addresses on the stack.
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the stack is empty.
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Pops all addresses from the stack.
+    #[inline(always)]
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<const N: usize> Default for FixedReturnStack<[MaybeUninit<ReturnAddr>; N]> {
+    #[inline(always)]
+    fn default() -> Self {
+        Self::new([MaybeUninit::uninit(); N])
+    }
+}
+
+impl<Addrs> ReturnStack for FixedReturnStack<Addrs>
+where
+    Addrs: AsMut<[MaybeUninit<ReturnAddr>]>,
+{
+    #[inline(always)]
+    fn push_return(&mut self, addr: ReturnAddr) -> Result<(), ReturnStackError> {
+        let slot = self
+            .addrs
+            .as_mut()
+            .get_mut(self.len)
+            .ok_or(ReturnStackError::Overflow)?;
+        *slot = MaybeUninit::new(addr);
+        self.len += 1;
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn pop_return(&mut self) -> Option<ReturnAddr> {
+        self.len = self.len.checked_sub(1)?;
+        // All the addresses below the length were pushed.
+        Some(unsafe { self.addrs.as_mut()[self.len].assume_init() })
+    }
+}
+
+impl<Addrs> fmt::Debug for FixedReturnStack<Addrs>
+where
+    Addrs: AsRef<[MaybeUninit<ReturnAddr>]>,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let addrs = &self.addrs.as_ref()[..self.len];
+        fmt.debug_list()
+            .entries(addrs.iter().map(|addr| unsafe { addr.assume_init() }))
+            .finish()
+    }
+}
+
+/// An opaque return address, pushed by `Call` and popped by `Ret`.
+#[derive(Clone, Copy)]
+pub struct ReturnAddr {
+    offset: usize,
+    build: BuildId,
+}
+
+impl fmt::Debug for ReturnAddr {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "ReturnAddr([base + {}])", self.offset)
+    }
+}
+
+/// An error that signals a misuse of the return stack.
+#[derive(Clone, Copy, Debug)]
+pub enum ReturnStackError {
+    /// `Call` was executed with a full return stack.
+    Overflow,
+    /// `Ret` was executed with an empty return stack.
+    Underflow,
+}
@@ -0,0 +1,198 @@
+//! Control-flow graphs of programs.
+
+use crate::debug_info::{DebugInfo, DebugInstruction, Line};
+use crate::Offset;
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+#[cfg(feature = "macros")]
+pub use naam_macros::Branches;
+
+/// Trait for values that may hold the offsets of operations to continue
+/// with, such as jump targets.
+///
+/// Operations implementing it can report it through `Execute::as_branches`,
+/// so that the control flow of a program can be recovered from its debug
+/// info. The default methods describe an operation that always continues
+/// with the next one on tape.
+pub trait Branches<'tape> {
+    /// Returns whether execution may continue with the next operation on
+    /// tape.
+    #[inline(always)]
+    fn falls_through(&self) -> bool {
+        true
+    }
+
+    /// Calls the given function with each offset execution may continue
+    /// with, other than the next operation on tape.
+    #[inline(always)]
+    fn branches(&self, branch: &mut dyn FnMut(Offset<'tape>)) {
+        let _ = branch;
+    }
+}
+
+impl<'tape> Branches<'tape> for Offset<'tape> {
+    #[inline(always)]
+    fn branches(&self, branch: &mut dyn FnMut(Offset<'tape>)) {
+        branch(*self)
+    }
+}
+
+macro_rules! no_branches {
+    ($($ty:ty),*) => {
+        $(
+            impl<'tape> Branches<'tape> for $ty {}
+        )*
+    };
+}
+
+// Primitive types don't hold offsets, so that they can be used as trailing
+// data.
+no_branches!(
+    (),
+    bool,
+    char,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64
+);
+
+/// Writes the control-flow graph of a program in the DOT format.
+///
+/// Operations whose successors are unknown end their block, which is linked
+/// to the next one by a dashed edge.
+pub(crate) fn write_dot(debug_info: DebugInfo, out: &mut dyn Write) -> fmt::Result {
+    let targets = Targets::new(debug_info);
+    out.write_str("digraph {\n    node [shape=box, fontname=monospace];\n")?;
+    let mut instructions = debug_info.instructions().peekable();
+    while let Some(leader) = instructions.next() {
+        write!(out, "    b{} [label=\"", usize::from(leader.offset()))?;
+        writeln!(Escape(out), "{}", Line(leader))?;
+        let mut last = leader;
+        while let Some(&next) = instructions.peek() {
+            if ends_block(last) || targets.contains(next.offset()) {
+                break;
+            }
+            writeln!(Escape(out), "{}", Line(next))?;
+            instructions.next();
+            last = next;
+        }
+        out.write_str("\"];\n")?;
+
+        let mut result = Ok(());
+        last.branches(|target| {
+            if result.is_ok() {
+                result = writeln!(
+                    out,
+                    "    b{} -> b{};",
+                    usize::from(leader.offset()),
+                    usize::from(target),
+                );
+            }
+        });
+        result?;
+        // Operations whose successors are unknown may fall through too.
+        let style = match last.falls_through() {
+            Some(true) => Some(""),
+            Some(false) => None,
+            None => Some(" [style=dashed]"),
+        };
+        if let (Some(style), Some(next)) = (style, instructions.peek()) {
+            writeln!(
+                out,
+                "    b{} -> b{}{};",
+                usize::from(leader.offset()),
+                usize::from(next.offset()),
+                style,
+            )?;
+        }
+    }
+    out.write_str("}\n")
+}
+
+/// Returns whether an operation is the last one of its basic block.
+fn ends_block(instruction: DebugInstruction) -> bool {
+    let mut branches = false;
+    instruction.branches(|_| branches = true);
+    branches || instruction.falls_through() != Some(true)
+}
+
+/// The offsets that operations may continue with, other than the next one
+/// on tape, sorted so they can be searched.
+#[cfg(feature = "alloc")]
+struct Targets(Vec<usize>);
+
+#[cfg(feature = "alloc")]
+impl Targets {
+    fn new(debug_info: DebugInfo) -> Self {
+        let mut targets = Vec::new();
+        for instruction in debug_info.instructions() {
+            instruction.branches(|target| targets.push(target.value));
+        }
+        targets.sort_unstable();
+        targets.dedup();
+        Self(targets)
+    }
+
+    /// Returns whether any operation may continue with the one at the given
+    /// offset, other than the one before it.
+    fn contains(&self, offset: Offset) -> bool {
+        self.0.binary_search(&offset.value).is_ok()
+    }
+}
+
+/// The operations of a program, scanned for each offset looked up, which
+/// takes time linear in the number of operations as there is nowhere to
+/// store their targets without allocating.
+#[cfg(not(feature = "alloc"))]
+struct Targets<'tape>(DebugInfo<'tape>);
+
+#[cfg(not(feature = "alloc"))]
+impl<'tape> Targets<'tape> {
+    fn new(debug_info: DebugInfo<'tape>) -> Self {
+        Self(debug_info)
+    }
+
+    /// Returns whether any operation may continue with the one at the given
+    /// offset, other than the one before it.
+    fn contains(&self, offset: Offset<'tape>) -> bool {
+        let mut found = false;
+        for instruction in self.0.instructions() {
+            instruction.branches(|target| found |= target.value == offset.value);
+            if found {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// A writer escaping the label of a DOT node, with lines justified left.
+struct Escape<'a>(&'a mut dyn Write);
+
+impl Write for Escape<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '"' => self.0.write_str("\\\"")?,
+                '\\' => self.0.write_str("\\\\")?,
+                '\n' => self.0.write_str("\\l")?,
+                c => self.0.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
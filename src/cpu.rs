@@ -1,8 +1,13 @@
 //! CPU-related traits and a couple of built-in CPUs.
 
-use crate::builtins::Unreachable;
+use crate::builtins::{Nop, Unreachable};
+use crate::debug_info::{DebugInfo, DebugInstruction};
 use crate::id::Id;
-use crate::{Destination, Execute, Pc, Runner};
+use crate::{Destination, Execute, Offset, Pc, Runner};
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use core::cmp::Reverse;
 use core::fmt;
 use core::mem;
 
@@ -10,28 +15,66 @@ use core::mem;
 ///
 /// A CPU dispatches operations based on which destination they return.
 /// They are all equipped with a `Unreachable` implementation to emit
-/// an operation that is guaranteed to panic, for safety reasons.
+/// an operation that is guaranteed to panic, for safety reasons, and with
+/// a `Nop` implementation to pad the tape before over-aligned operations.
 ///
 /// It is the CPU's responsibility to ensure the proper progression of the
 /// program through the opaque `DispatchToken` values reachable from the
 /// destinations returned by each operation, .
-pub trait Dispatch<Ram>: Copy
+pub trait Dispatch<Ram, Out = ()>: Copy
 where
-    for<'tape> Self: GetDispatchToken<'tape, Unreachable, Ram>,
+    for<'tape> Self:
+        GetDispatchToken<'tape, Unreachable, Ram, Out> + GetDispatchToken<'tape, Nop, Ram, Out>,
     Ram: ?Sized,
 {
     /// Dispatches the operation at the given address.
-    unsafe fn dispatch<'tape>(self, addr: Addr<'tape>, runner: Runner<'tape>, ram: &mut Ram);
+    ///
+    /// # Safety
+    ///
+    /// The address and the runner must come from the same tape, which must
+    /// have been built with this CPU.
+    unsafe fn dispatch<'tape>(
+        self,
+        addr: Addr<'tape>,
+        runner: Runner<'tape>,
+        ram: &mut Ram,
+    ) -> Halt<'tape, Out>;
+
+    /// Dispatches the operation at the given address, with access to the
+    /// debug info of the program.
+    ///
+    /// This is what `Program` calls, and it defaults to `Dispatch::dispatch`.
+    ///
+    /// # Safety
+    ///
+    /// See `Dispatch::dispatch`. The debug info must come from the same
+    /// program as the tape.
+    #[inline(always)]
+    unsafe fn dispatch_with_debug_info<'tape>(
+        self,
+        addr: Addr<'tape>,
+        runner: Runner<'tape>,
+        ram: &mut Ram,
+        debug_info: DebugInfo<'tape>,
+    ) -> Halt<'tape, Out> {
+        let _ = debug_info;
+        self.dispatch(addr, runner, ram)
+    }
 }
 
 /// CPUs should implement this trait for each operation they support.
 ///
+/// # Safety
+///
+/// The returned dispatch token must be one that `Dispatch::dispatch` knows
+/// how to execute as an `Op`.
+///
 /// **Note:** Implementors of this trait should also implement
-/// `Dispatch<Ram, Env>`, but such a where clause would introduce a cycle
+/// `Dispatch<Ram, Out>`, but such a where clause would introduce a cycle
 /// because of the `GetDispatchToken` bound in the definition of `Dispatch`.
-pub unsafe trait GetDispatchToken<'tape, Op, Ram>: Copy
+pub unsafe trait GetDispatchToken<'tape, Op, Ram, Out = ()>: Copy
 where
-    Op: Execute<'tape, Ram>,
+    Op: Execute<'tape, Ram, Out>,
     Ram: ?Sized,
 {
     /// Returns the dispatch token for this operation.
@@ -41,6 +84,42 @@ where
     fn get_dispatch_token(self) -> DispatchToken;
 }
 
+/// CPUs should implement this trait for each type of trailing data they
+/// support, on top of `GetDispatchToken` for the operations followed by it.
+///
+/// This lets CPUs such as `ThreadSafe` restrict what can be stored on tape
+/// after an operation, see `Trailing`.
+pub trait AcceptTrailing<Item>: Copy {}
+
+/// CPUs that dispatch operations one at a time from a loop.
+///
+/// Such CPUs can be wrapped by other CPUs that need to do something between
+/// two operations, such as `Metered`.
+///
+/// # Safety
+///
+/// `Dispatch::dispatch` must be equivalent to calling `Step::step` in a loop
+/// until it returns a halt token.
+pub unsafe trait Step<Ram, Out = ()>: Dispatch<Ram, Out>
+where
+    for<'tape> Self:
+        GetDispatchToken<'tape, Unreachable, Ram, Out> + GetDispatchToken<'tape, Nop, Ram, Out>,
+    Ram: ?Sized,
+{
+    /// Executes the operation at the given address, returning the next
+    /// destination without dispatching it.
+    ///
+    /// # Safety
+    ///
+    /// See `Dispatch::dispatch`.
+    unsafe fn step<'tape>(
+        self,
+        addr: Addr<'tape>,
+        runner: Runner<'tape>,
+        ram: &mut Ram,
+    ) -> Destination<'tape, Out>;
+}
+
 /// An opaque dispatch token.
 ///
 /// Opaque dispatch tokens can be converted to and from usize values through
@@ -83,19 +162,32 @@ impl<'tape> Addr<'tape> {
 
 /// Token to signal that the program should halt.
 ///
-/// This is returned by `Runner::halt`.
+/// This is returned by `Runner::halt`, `Runner::halt_with` and `Runner::pause`.
 #[derive(Clone, Copy)]
-pub struct Halt<'tape> {
+pub struct Halt<'tape, Out = ()> {
+    pub(crate) reason: Reason<'tape, Out>,
     #[allow(dead_code)]
     pub(crate) id: Id<'tape>,
 }
 
-impl fmt::Debug for Halt<'_> {
+impl<Out> fmt::Debug for Halt<'_, Out>
+where
+    Out: fmt::Debug,
+{
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        fmt.write_str("Halt")
+        match &self.reason {
+            Reason::Halted(value) => fmt.debug_tuple("Halt").field(value).finish(),
+            Reason::Paused(_) => fmt.write_str("Pause"),
+        }
     }
 }
 
+#[derive(Clone, Copy)]
+pub(crate) enum Reason<'tape, Out> {
+    Halted(Out),
+    Paused(Addr<'tape>),
+}
+
 /// A CPU that dispatches operations looping and calling operations directly.
 ///
 /// This CPU supports all instructions.
@@ -108,49 +200,73 @@ impl fmt::Debug for Halt<'_> {
 #[derive(Clone, Copy, Debug)]
 pub struct DirectThreadedLoop;
 
-unsafe impl<'tape, Op, Ram> GetDispatchToken<'tape, Op, Ram> for DirectThreadedLoop
+unsafe impl<'tape, Op, Ram, Out> GetDispatchToken<'tape, Op, Ram, Out> for DirectThreadedLoop
 where
-    Op: Execute<'tape, Ram>,
+    Op: Execute<'tape, Ram, Out>,
     Ram: ?Sized,
 {
     #[inline(always)]
     fn get_dispatch_token(self) -> DispatchToken {
         // The dispatch token here is a function that returns a destination, as
         // Self.dispatch loops over return values from this function.
-        unsafe fn exec<'tape, Op, Ram>(
+        unsafe fn exec<'tape, Op, Ram, Out>(
             addr: Addr<'tape>,
             runner: Runner<'tape>,
             ram: &mut Ram,
-        ) -> Destination<'tape>
+        ) -> Destination<'tape, Out>
         where
-            Op: Execute<'tape, Ram>,
+            Op: Execute<'tape, Ram, Out>,
             Ram: ?Sized,
         {
-            Op::execute(Pc::from_addr(addr), runner, ram)
+            Op::execute(Pc::from_addr::<Ram, Out>(addr), runner, ram)
         }
 
-        DispatchToken::from(exec::<Op, Ram> as OpaqueExec<'tape, Ram, Destination<'tape>> as usize)
+        DispatchToken::from(
+            exec::<Op, Ram, Out> as OpaqueExec<'tape, Ram, Destination<'tape, Out>> as usize,
+        )
     }
 }
 
-impl<Ram> Dispatch<Ram> for DirectThreadedLoop
+impl<Item> AcceptTrailing<Item> for DirectThreadedLoop {}
+
+impl<Ram, Out> Dispatch<Ram, Out> for DirectThreadedLoop
 where
     Ram: ?Sized,
 {
     #[inline(always)]
-    unsafe fn dispatch<'tape>(self, mut addr: Addr<'tape>, runner: Runner<'tape>, ram: &mut Ram) {
+    unsafe fn dispatch<'tape>(
+        self,
+        mut addr: Addr<'tape>,
+        runner: Runner<'tape>,
+        ram: &mut Ram,
+    ) -> Halt<'tape, Out> {
         loop {
-            let function = mem::transmute::<usize, OpaqueExec<'tape, Ram, Destination<'tape>>>(
-                addr.token().into(),
-            );
-            match function(addr, runner, ram) {
+            match self.step(addr, runner, ram) {
                 Ok(next) => addr = next,
-                Err(_) => return,
+                Err(halt) => return halt,
             }
         }
     }
 }
 
+unsafe impl<Ram, Out> Step<Ram, Out> for DirectThreadedLoop
+where
+    Ram: ?Sized,
+{
+    #[inline(always)]
+    unsafe fn step<'tape>(
+        self,
+        addr: Addr<'tape>,
+        runner: Runner<'tape>,
+        ram: &mut Ram,
+    ) -> Destination<'tape, Out> {
+        let function = mem::transmute::<usize, OpaqueExec<'tape, Ram, Destination<'tape, Out>>>(
+            addr.token().into(),
+        );
+        function(addr, runner, ram)
+    }
+}
+
 /// A CPU that dispatches operations the way a direct-threaded emulator does.
 ///
 /// This CPU supports all instructions.
@@ -161,43 +277,832 @@ where
 #[derive(Clone, Copy, Debug)]
 pub struct DirectThreadedCall;
 
-unsafe impl<'tape, Op, Ram> GetDispatchToken<'tape, Op, Ram> for DirectThreadedCall
+unsafe impl<'tape, Op, Ram, Out> GetDispatchToken<'tape, Op, Ram, Out> for DirectThreadedCall
 where
-    Op: Execute<'tape, Ram>,
+    Op: Execute<'tape, Ram, Out>,
     Ram: ?Sized,
 {
     #[inline(always)]
     fn get_dispatch_token(self) -> DispatchToken {
-        // The dispatch token here is a function that returns (), as it
-        // calls Self.dispatch directly.
-        unsafe fn exec<'tape, Op, Ram>(
+        // The dispatch token here is a function that returns the halt token,
+        // as it calls Self.dispatch directly.
+        unsafe fn exec<'tape, Op, Ram, Out>(
             addr: Addr<'tape>,
             runner: Runner<'tape>,
             ram: &mut Ram,
-        ) where
-            Op: Execute<'tape, Ram>,
+        ) -> Halt<'tape, Out>
+        where
+            Op: Execute<'tape, Ram, Out>,
             Ram: ?Sized,
         {
-            match Op::execute(Pc::from_addr(addr), runner, ram) {
-                Ok(addr) => Self.dispatch(addr, runner, ram),
-                Err(_) => (),
+            match Op::execute(Pc::from_addr::<Ram, Out>(addr), runner, ram) {
+                Ok(addr) => DirectThreadedCall.dispatch(addr, runner, ram),
+                Err(halt) => halt,
             }
         }
 
-        DispatchToken::from(exec::<Op, Ram> as OpaqueExec<'tape, Ram, ()> as usize)
+        DispatchToken::from(
+            exec::<Op, Ram, Out> as OpaqueExec<'tape, Ram, Halt<'tape, Out>> as usize,
+        )
     }
 }
 
-impl<Ram> Dispatch<Ram> for DirectThreadedCall
+impl<Item> AcceptTrailing<Item> for DirectThreadedCall {}
+
+impl<Ram, Out> Dispatch<Ram, Out> for DirectThreadedCall
 where
     Ram: ?Sized,
 {
     #[inline(always)]
-    unsafe fn dispatch<'tape>(self, addr: Addr<'tape>, runner: Runner<'tape>, ram: &mut Ram) {
+    unsafe fn dispatch<'tape>(
+        self,
+        addr: Addr<'tape>,
+        runner: Runner<'tape>,
+        ram: &mut Ram,
+    ) -> Halt<'tape, Out> {
         let function =
-            mem::transmute::<usize, OpaqueExec<'tape, Ram, ()>>(addr.token().into());
+            mem::transmute::<usize, OpaqueExec<'tape, Ram, Halt<'tape, Out>>>(addr.token().into());
         function(addr, runner, ram)
     }
 }
 
-type OpaqueExec<'tape, Ram, Out> = unsafe fn(Addr<'tape>, Runner<'tape>, &mut Ram) -> Out;
+/// A CPU that dispatches operations through the jump table of an instruction
+/// set.
+///
+/// This CPU only supports the operations of its instruction set, for which
+/// the dispatch token is a small opcode instead of a function pointer, so
+/// tapes built with it don't contain any address specific to the process
+/// that built them.
+#[derive(Clone, Copy, Debug)]
+pub struct TokenThreaded<Set>(pub Set);
+
+unsafe impl<'tape, Op, Ram, Out, Set> GetDispatchToken<'tape, Op, Ram, Out> for TokenThreaded<Set>
+where
+    Op: Execute<'tape, Ram, Out>,
+    Ram: ?Sized,
+    Set: Opcode<'tape, Op, Ram, Out>,
+{
+    #[inline(always)]
+    fn get_dispatch_token(self) -> DispatchToken {
+        DispatchToken::from(usize::from(Set::OPCODE))
+    }
+}
+
+impl<Item, Set> AcceptTrailing<Item> for TokenThreaded<Set> where Set: Copy {}
+
+impl<Ram, Out, Set> Dispatch<Ram, Out> for TokenThreaded<Set>
+where
+    for<'tape> Set: Opcode<'tape, Unreachable, Ram, Out> + Opcode<'tape, Nop, Ram, Out>,
+    Ram: ?Sized,
+{
+    #[inline(always)]
+    unsafe fn dispatch<'tape>(
+        self,
+        mut addr: Addr<'tape>,
+        runner: Runner<'tape>,
+        ram: &mut Ram,
+    ) -> Halt<'tape, Out> {
+        loop {
+            match self.step(addr, runner, ram) {
+                Ok(next) => addr = next,
+                Err(halt) => return halt,
+            }
+        }
+    }
+}
+
+unsafe impl<Ram, Out, Set> Step<Ram, Out> for TokenThreaded<Set>
+where
+    for<'tape> Set: Opcode<'tape, Unreachable, Ram, Out> + Opcode<'tape, Nop, Ram, Out>,
+    Ram: ?Sized,
+{
+    #[inline(always)]
+    unsafe fn step<'tape>(
+        self,
+        addr: Addr<'tape>,
+        runner: Runner<'tape>,
+        ram: &mut Ram,
+    ) -> Destination<'tape, Out> {
+        let exec = self.0.exec(usize::from(addr.token()) as u16);
+        (exec.0)(addr, runner, ram)
+    }
+}
+
+/// A CPU wrapping another one to pause programs once they run out of fuel.
+///
+/// Every dispatched operation consumes one unit of fuel. When there is none
+/// left, the program pauses before executing the next operation, and
+/// `Program::run` returns a continuation that can be passed to
+/// `Program::resume` after refuelling.
+///
+/// This CPU supports all instructions supported by the wrapped CPU.
+#[derive(Clone, Copy, Debug)]
+pub struct Metered<'fuel, Cpu> {
+    cpu: Cpu,
+    fuel: &'fuel Fuel,
+}
+
+impl<'fuel, Cpu> Metered<'fuel, Cpu> {
+    /// Wraps a CPU, drawing fuel from the given tank.
+    #[inline(always)]
+    pub fn new(cpu: Cpu, fuel: &'fuel Fuel) -> Self {
+        Self { cpu, fuel }
+    }
+}
+
+unsafe impl<'tape, 'fuel, Op, Ram, Out, Cpu> GetDispatchToken<'tape, Op, Ram, Out>
+    for Metered<'fuel, Cpu>
+where
+    Op: Execute<'tape, Ram, Out>,
+    Ram: ?Sized,
+    Cpu: GetDispatchToken<'tape, Op, Ram, Out>,
+{
+    #[inline(always)]
+    fn get_dispatch_token(self) -> DispatchToken {
+        self.cpu.get_dispatch_token()
+    }
+}
+
+impl<Item, Cpu> AcceptTrailing<Item> for Metered<'_, Cpu> where Cpu: AcceptTrailing<Item> {}
+
+impl<'fuel, Ram, Out, Cpu> Dispatch<Ram, Out> for Metered<'fuel, Cpu>
+where
+    Cpu: Step<Ram, Out>,
+    for<'tape> Cpu:
+        GetDispatchToken<'tape, Unreachable, Ram, Out> + GetDispatchToken<'tape, Nop, Ram, Out>,
+    Ram: ?Sized,
+{
+    #[inline(always)]
+    unsafe fn dispatch<'tape>(
+        self,
+        mut addr: Addr<'tape>,
+        runner: Runner<'tape>,
+        ram: &mut Ram,
+    ) -> Halt<'tape, Out> {
+        self.fuel.exhausted.set(false);
+        loop {
+            let remaining = self.fuel.remaining.get();
+            if remaining == 0 {
+                self.fuel.exhausted.set(true);
+                return runner.pause(addr);
+            }
+            self.fuel.remaining.set(remaining - 1);
+            match self.cpu.step(addr, runner, ram) {
+                Ok(next) => addr = next,
+                Err(halt) => return halt,
+            }
+        }
+    }
+}
+
+/// The fuel consumed by a `Metered` CPU.
+#[derive(Debug, Default)]
+pub struct Fuel {
+    remaining: Cell<u64>,
+    exhausted: Cell<bool>,
+}
+
+impl Fuel {
+    /// Returns a new tank with the given amount of fuel.
+    #[inline(always)]
+    pub fn new(amount: u64) -> Self {
+        Self {
+            remaining: Cell::new(amount),
+            exhausted: Cell::new(false),
+        }
+    }
+
+    /// Returns the remaining amount of fuel.
+    #[inline(always)]
+    pub fn remaining(&self) -> u64 {
+        self.remaining.get()
+    }
+
+    /// Adds some fuel to the tank.
+    #[inline(always)]
+    pub fn refuel(&self, amount: u64) {
+        self.remaining
+            .set(self.remaining.get().saturating_add(amount));
+    }
+
+    /// Returns whether the last program run with this fuel paused because
+    /// it ran out of it.
+    #[inline(always)]
+    pub fn is_exhausted(&self) -> bool {
+        self.exhausted.get()
+    }
+}
+
+/// A CPU wrapping another one to trace every operation it dispatches.
+///
+/// Before executing an operation, this CPU passes its offset to the tracer,
+/// along with the operation as recorded in the debug info of the program
+/// when it was recorded there.
+///
+/// This CPU supports all instructions supported by the wrapped CPU.
+pub struct Traced<'trace, Cpu, T>
+where
+    T: ?Sized,
+{
+    cpu: Cpu,
+    tracer: &'trace T,
+}
+
+impl<Cpu, T> Clone for Traced<'_, Cpu, T>
+where
+    Cpu: Copy,
+    T: ?Sized,
+{
+    #[inline(always)]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<Cpu, T> Copy for Traced<'_, Cpu, T>
+where
+    Cpu: Copy,
+    T: ?Sized,
+{
+}
+
+impl<Cpu, T> fmt::Debug for Traced<'_, Cpu, T>
+where
+    Cpu: fmt::Debug,
+    T: ?Sized,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_tuple("Traced").field(&self.cpu).finish()
+    }
+}
+
+impl<'trace, Cpu, T> Traced<'trace, Cpu, T>
+where
+    T: ?Sized,
+{
+    /// Wraps a CPU, passing the operations it dispatches to the given
+    /// tracer.
+    #[inline(always)]
+    pub fn new(cpu: Cpu, tracer: &'trace T) -> Self {
+        Self { cpu, tracer }
+    }
+
+    unsafe fn trace<'tape, Ram, Out>(
+        self,
+        mut addr: Addr<'tape>,
+        runner: Runner<'tape>,
+        ram: &mut Ram,
+        debug_info: Option<DebugInfo<'tape>>,
+    ) -> Halt<'tape, Out>
+    where
+        Cpu: Step<Ram, Out>,
+        for<'a> Cpu:
+            GetDispatchToken<'a, Unreachable, Ram, Out> + GetDispatchToken<'a, Nop, Ram, Out>,
+        Ram: ?Sized,
+        T: Trace,
+    {
+        loop {
+            let offset = runner.offset_of(addr);
+            let instruction = debug_info.and_then(|info| info.instruction_at(offset));
+            self.tracer.trace(offset, instruction);
+            match self.cpu.step(addr, runner, ram) {
+                Ok(next) => addr = next,
+                Err(halt) => return halt,
+            }
+        }
+    }
+}
+
+unsafe impl<'tape, 'trace, Op, Ram, Out, Cpu, T> GetDispatchToken<'tape, Op, Ram, Out>
+    for Traced<'trace, Cpu, T>
+where
+    Op: Execute<'tape, Ram, Out>,
+    Ram: ?Sized,
+    Cpu: GetDispatchToken<'tape, Op, Ram, Out>,
+    T: ?Sized,
+{
+    #[inline(always)]
+    fn get_dispatch_token(self) -> DispatchToken {
+        self.cpu.get_dispatch_token()
+    }
+}
+
+impl<Item, Cpu, T> AcceptTrailing<Item> for Traced<'_, Cpu, T>
+where
+    Cpu: AcceptTrailing<Item>,
+    T: ?Sized,
+{
+}
+
+impl<'trace, Ram, Out, Cpu, T> Dispatch<Ram, Out> for Traced<'trace, Cpu, T>
+where
+    Cpu: Step<Ram, Out>,
+    for<'tape> Cpu:
+        GetDispatchToken<'tape, Unreachable, Ram, Out> + GetDispatchToken<'tape, Nop, Ram, Out>,
+    Ram: ?Sized,
+    T: Trace + ?Sized,
+{
+    #[inline(always)]
+    unsafe fn dispatch<'tape>(
+        self,
+        addr: Addr<'tape>,
+        runner: Runner<'tape>,
+        ram: &mut Ram,
+    ) -> Halt<'tape, Out> {
+        self.trace(addr, runner, ram, None)
+    }
+
+    #[inline(always)]
+    unsafe fn dispatch_with_debug_info<'tape>(
+        self,
+        addr: Addr<'tape>,
+        runner: Runner<'tape>,
+        ram: &mut Ram,
+        debug_info: DebugInfo<'tape>,
+    ) -> Halt<'tape, Out> {
+        self.trace(addr, runner, ram, Some(debug_info))
+    }
+}
+
+/// A tracer, receiving the operations dispatched by a `Traced` CPU.
+///
+/// This is implemented for closures, and for `RefCell<W>` where `W`
+/// implements `fmt::Write`, writing one line per operation and ignoring
+/// errors.
+pub trait Trace {
+    /// Receives the offset of an operation about to be executed, and the
+    /// operation itself if it was recorded in the debug info.
+    fn trace<'tape>(&self, offset: Offset<'tape>, instruction: Option<DebugInstruction<'tape>>);
+}
+
+impl<F> Trace for F
+where
+    F: for<'tape> Fn(Offset<'tape>, Option<DebugInstruction<'tape>>),
+{
+    #[inline(always)]
+    fn trace<'tape>(&self, offset: Offset<'tape>, instruction: Option<DebugInstruction<'tape>>) {
+        self(offset, instruction)
+    }
+}
+
+impl<W> Trace for RefCell<W>
+where
+    W: fmt::Write,
+{
+    fn trace<'tape>(&self, offset: Offset<'tape>, instruction: Option<DebugInstruction<'tape>>) {
+        let mut writer = self.borrow_mut();
+        let _ = match instruction {
+            Some(instruction) => writeln!(writer, "{:?}: {:?}", offset, instruction),
+            None => writeln!(writer, "{:?}", offset),
+        };
+    }
+}
+
+/// A CPU wrapping another one to only support operations that are `Send`
+/// and `Sync`, and trailing data that is too.
+///
+/// Programs built with this CPU are `Send` and `Sync` if the wrapped CPU,
+/// their tapes and their code are, so that they can be run concurrently from
+/// many threads, each with its own RAM.
+#[derive(Clone, Copy, Debug)]
+pub struct ThreadSafe<Cpu>(pub Cpu);
+
+unsafe impl<'tape, Op, Ram, Out, Cpu> GetDispatchToken<'tape, Op, Ram, Out> for ThreadSafe<Cpu>
+where
+    Op: Execute<'tape, Ram, Out> + Send + Sync,
+    Ram: ?Sized,
+    Cpu: GetDispatchToken<'tape, Op, Ram, Out>,
+{
+    #[inline(always)]
+    fn get_dispatch_token(self) -> DispatchToken {
+        self.0.get_dispatch_token()
+    }
+}
+
+// Trailing data is shared along with the program, just like operations.
+impl<Item, Cpu> AcceptTrailing<Item> for ThreadSafe<Cpu>
+where
+    Item: Send + Sync,
+    Cpu: AcceptTrailing<Item>,
+{
+}
+
+impl<Ram, Out, Cpu> Dispatch<Ram, Out> for ThreadSafe<Cpu>
+where
+    Cpu: Dispatch<Ram, Out>,
+    for<'tape> Cpu:
+        GetDispatchToken<'tape, Unreachable, Ram, Out> + GetDispatchToken<'tape, Nop, Ram, Out>,
+    Ram: ?Sized,
+{
+    #[inline(always)]
+    unsafe fn dispatch<'tape>(
+        self,
+        addr: Addr<'tape>,
+        runner: Runner<'tape>,
+        ram: &mut Ram,
+    ) -> Halt<'tape, Out> {
+        self.0.dispatch(addr, runner, ram)
+    }
+
+    #[inline(always)]
+    unsafe fn dispatch_with_debug_info<'tape>(
+        self,
+        addr: Addr<'tape>,
+        runner: Runner<'tape>,
+        ram: &mut Ram,
+        debug_info: DebugInfo<'tape>,
+    ) -> Halt<'tape, Out> {
+        self.0
+            .dispatch_with_debug_info(addr, runner, ram, debug_info)
+    }
+}
+
+unsafe impl<Ram, Out, Cpu> Step<Ram, Out> for ThreadSafe<Cpu>
+where
+    Cpu: Step<Ram, Out>,
+    for<'tape> Cpu:
+        GetDispatchToken<'tape, Unreachable, Ram, Out> + GetDispatchToken<'tape, Nop, Ram, Out>,
+    Ram: ?Sized,
+{
+    #[inline(always)]
+    unsafe fn step<'tape>(
+        self,
+        addr: Addr<'tape>,
+        runner: Runner<'tape>,
+        ram: &mut Ram,
+    ) -> Destination<'tape, Out> {
+        self.0.step(addr, runner, ram)
+    }
+}
+
+/// A CPU wrapping another one to profile the operations it dispatches.
+///
+/// Every dispatched operation increments the count of the sample for its
+/// offset in the profile, and adds the time it took to execute according to
+/// the profile's clock.
+///
+/// This CPU supports all instructions supported by the wrapped CPU.
+pub struct Profiled<'profile, Cpu, Samples, C = NoClock> {
+    cpu: Cpu,
+    profile: &'profile Profile<Samples, C>,
+}
+
+impl<'profile, Cpu, Samples, C> Profiled<'profile, Cpu, Samples, C> {
+    /// Wraps a CPU, recording samples in the given profile.
+    #[inline(always)]
+    pub fn new(cpu: Cpu, profile: &'profile Profile<Samples, C>) -> Self {
+        Self { cpu, profile }
+    }
+}
+
+impl<Cpu, Samples, C> Clone for Profiled<'_, Cpu, Samples, C>
+where
+    Cpu: Copy,
+{
+    #[inline(always)]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<Cpu, Samples, C> Copy for Profiled<'_, Cpu, Samples, C> where Cpu: Copy {}
+
+impl<Cpu, Samples, C> fmt::Debug for Profiled<'_, Cpu, Samples, C>
+where
+    Cpu: fmt::Debug,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_tuple("Profiled").field(&self.cpu).finish()
+    }
+}
+
+unsafe impl<'tape, 'profile, Op, Ram, Out, Cpu, Samples, C> GetDispatchToken<'tape, Op, Ram, Out>
+    for Profiled<'profile, Cpu, Samples, C>
+where
+    Op: Execute<'tape, Ram, Out>,
+    Ram: ?Sized,
+    Cpu: GetDispatchToken<'tape, Op, Ram, Out>,
+{
+    #[inline(always)]
+    fn get_dispatch_token(self) -> DispatchToken {
+        self.cpu.get_dispatch_token()
+    }
+}
+
+impl<Item, Cpu, Samples, C> AcceptTrailing<Item> for Profiled<'_, Cpu, Samples, C> where
+    Cpu: AcceptTrailing<Item>
+{
+}
+
+impl<'profile, Ram, Out, Cpu, Samples, C> Dispatch<Ram, Out> for Profiled<'profile, Cpu, Samples, C>
+where
+    Cpu: Step<Ram, Out>,
+    for<'tape> Cpu:
+        GetDispatchToken<'tape, Unreachable, Ram, Out> + GetDispatchToken<'tape, Nop, Ram, Out>,
+    Ram: ?Sized,
+    Samples: AsRef<[Sample]>,
+    C: Clock,
+{
+    #[inline(always)]
+    unsafe fn dispatch<'tape>(
+        self,
+        mut addr: Addr<'tape>,
+        runner: Runner<'tape>,
+        ram: &mut Ram,
+    ) -> Halt<'tape, Out> {
+        let samples = self.profile.samples.as_ref();
+        let clock = &self.profile.clock;
+        loop {
+            let index = runner.offset_of(addr).value / mem::size_of::<usize>();
+            let start = clock.now();
+            let destination = self.cpu.step(addr, runner, ram);
+            let elapsed = clock.now().wrapping_sub(start);
+            if let Some(sample) = samples.get(index) {
+                sample.count.set(sample.count.get().wrapping_add(1));
+                sample
+                    .elapsed
+                    .set(sample.elapsed.get().wrapping_add(elapsed));
+            }
+            match destination {
+                Ok(next) => addr = next,
+                Err(halt) => return halt,
+            }
+        }
+    }
+}
+
+/// The samples recorded by a `Profiled` CPU.
+///
+/// There is one sample per word of the tape, so `Samples` should be at
+/// least as long as the tape, typically `Vec<Sample>` or `[Sample; N]`.
+/// Operations whose offset falls outside of it are not recorded.
+#[derive(Debug)]
+pub struct Profile<Samples, C = NoClock> {
+    samples: Samples,
+    clock: C,
+}
+
+impl<Samples> Profile<Samples>
+where
+    Samples: AsRef<[Sample]>,
+{
+    /// Returns a new profile recording samples in the given storage, without
+    /// measuring time.
+    #[inline(always)]
+    pub fn new(samples: Samples) -> Self {
+        Self::with_clock(samples, NoClock)
+    }
+}
+
+impl<Samples, C> Profile<Samples, C>
+where
+    Samples: AsRef<[Sample]>,
+    C: Clock,
+{
+    /// Returns a new profile recording samples in the given storage, and
+    /// measuring time with the given clock.
+    #[inline(always)]
+    pub fn with_clock(samples: Samples, clock: C) -> Self {
+        Self { samples, clock }
+    }
+
+    /// Returns the sample for the operation at the given offset, if any.
+    #[inline(always)]
+    pub fn sample(&self, offset: Offset) -> Option<&Sample> {
+        self.samples
+            .as_ref()
+            .get(offset.value / mem::size_of::<usize>())
+    }
+
+    /// Resets all samples.
+    pub fn reset(&self) {
+        for sample in self.samples.as_ref() {
+            sample.count.set(0);
+            sample.elapsed.set(0);
+        }
+    }
+
+    /// Returns a report of the operations that were executed, ranked by
+    /// how many times they were executed.
+    ///
+    /// The operations are disassembled using the given debug info, which
+    /// should be the one of the profiled program.
+    #[inline(always)]
+    pub fn report<'a, 'tape>(&'a self, debug_info: DebugInfo<'tape>) -> Report<'a, 'tape> {
+        Report {
+            samples: self.samples.as_ref(),
+            debug_info,
+            by_elapsed: false,
+        }
+    }
+}
+
+/// The number of times an operation was executed, and the time it took.
+#[derive(Clone, Debug, Default)]
+pub struct Sample {
+    count: Cell<u64>,
+    elapsed: Cell<u64>,
+}
+
+impl Sample {
+    /// Returns how many times the operation was executed.
+    #[inline(always)]
+    pub fn count(&self) -> u64 {
+        self.count.get()
+    }
+
+    /// Returns the total time spent executing the operation, as measured by
+    /// the profile's clock.
+    #[inline(always)]
+    pub fn elapsed(&self) -> u64 {
+        self.elapsed.get()
+    }
+}
+
+/// A clock used by profiles to measure the time spent in operations.
+pub trait Clock {
+    /// Returns the current time, in any unit as long as it is monotonic.
+    fn now(&self) -> u64;
+}
+
+/// A clock that never advances, for profiles that only count operations.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoClock;
+
+impl Clock for NoClock {
+    #[inline(always)]
+    fn now(&self) -> u64 {
+        0
+    }
+}
+
+/// A ranked, disassembled report of a profile.
+///
+/// Its `Display` implementation writes one line per executed operation,
+/// with its count, the time spent executing it, its offset and the
+/// operation itself.
+#[derive(Clone, Copy)]
+pub struct Report<'a, 'tape> {
+    samples: &'a [Sample],
+    debug_info: DebugInfo<'tape>,
+    by_elapsed: bool,
+}
+
+impl<'a, 'tape> Report<'a, 'tape> {
+    /// Ranks the operations by the time spent executing them instead.
+    #[inline(always)]
+    pub fn by_elapsed(self) -> Self {
+        Self {
+            by_elapsed: true,
+            ..self
+        }
+    }
+
+    /// Returns the rank of the sample at the given index, lower is hotter.
+    #[inline(always)]
+    fn rank(&self, index: usize) -> (Reverse<u64>, usize) {
+        let sample = &self.samples[index];
+        let key = if self.by_elapsed {
+            sample.elapsed()
+        } else {
+            sample.count()
+        };
+        (Reverse(key), index)
+    }
+
+    /// Writes the line of the report for the sample at the given index.
+    fn write_line(&self, fmt: &mut fmt::Formatter, index: usize) -> fmt::Result {
+        let sample = &self.samples[index];
+        let offset = Offset {
+            value: index * mem::size_of::<usize>(),
+            id: Id::default(),
+        };
+        write!(
+            fmt,
+            "{:>12} {:>12}  {:?}: ",
+            sample.count(),
+            sample.elapsed(),
+            offset,
+        )?;
+        match self.debug_info.instruction_at(offset) {
+            Some(instruction) => writeln!(fmt, "{:?}", instruction),
+            None => writeln!(fmt, ".."),
+        }
+    }
+}
+
+impl fmt::Display for Report<'_, '_> {
+    #[cfg(feature = "alloc")]
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(fmt, "{:>12} {:>12}  instruction", "count", "elapsed")?;
+        let mut indices = (0..self.samples.len())
+            .filter(|&index| self.samples[index].count() != 0)
+            .collect::<Vec<_>>();
+        indices.sort_unstable_by_key(|&index| self.rank(index));
+        for index in indices {
+            self.write_line(fmt, index)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "alloc"))]
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(fmt, "{:>12} {:>12}  instruction", "count", "elapsed")?;
+        // Without alloc, the samples can't be sorted, so each line looks for
+        // the next hottest sample instead, `None` being lower than any rank.
+        let mut previous = None;
+        loop {
+            let next = (0..self.samples.len())
+                .filter(|&index| self.samples[index].count() != 0)
+                .map(|index| self.rank(index))
+                .filter(|&rank| Some(rank) > previous)
+                .min();
+            let rank = match next {
+                Some(rank) => rank,
+                None => return Ok(()),
+            };
+            self.write_line(fmt, rank.1)?;
+            previous = Some(rank);
+        }
+    }
+}
+
+/// An instruction set, to be used with `TokenThreaded`.
+///
+/// Instruction sets must include `Unreachable` and `Nop`, which are emitted
+/// by the builder itself.
+///
+/// # Safety
+///
+/// For every operation `Op` for which `Self` implements `Opcode`,
+/// `self.exec(<Self as Opcode<Op, Ram>>::OPCODE)` must return
+/// `Exec::new::<Op>()` (modulo lifetimes).
+pub unsafe trait InstructionSet<Ram, Out = ()>: Copy
+where
+    Ram: ?Sized,
+{
+    /// Returns the entry of the jump table for the given opcode.
+    ///
+    /// This is only ever called with opcodes declared through `Opcode`.
+    fn exec<'tape>(self, opcode: u16) -> Exec<'tape, Ram, Out>;
+}
+
+/// Instruction sets should implement this trait for each operation they
+/// include.
+///
+/// # Safety
+///
+/// See `InstructionSet`.
+pub unsafe trait Opcode<'tape, Op, Ram, Out = ()>: InstructionSet<Ram, Out>
+where
+    Op: Execute<'tape, Ram, Out>,
+    Ram: ?Sized,
+{
+    /// The opcode of the operation in this instruction set.
+    const OPCODE: u16;
+}
+
+/// An entry in the jump table of an instruction set.
+pub struct Exec<'tape, Ram, Out = ()>(OpaqueExec<'tape, Ram, Destination<'tape, Out>>)
+where
+    Ram: ?Sized;
+
+impl<'tape, Ram, Out> Exec<'tape, Ram, Out>
+where
+    Ram: ?Sized,
+{
+    /// Returns the entry executing operations of type `Op`.
+    #[inline(always)]
+    pub const fn new<Op>() -> Self
+    where
+        Op: Execute<'tape, Ram, Out>,
+    {
+        unsafe fn exec<'tape, Op, Ram, Out>(
+            addr: Addr<'tape>,
+            runner: Runner<'tape>,
+            ram: &mut Ram,
+        ) -> Destination<'tape, Out>
+        where
+            Op: Execute<'tape, Ram, Out>,
+            Ram: ?Sized,
+        {
+            Op::execute(Pc::from_addr::<Ram, Out>(addr), runner, ram)
+        }
+
+        Self(exec::<Op, Ram, Out>)
+    }
+}
+
+impl<Ram, Out> Clone for Exec<'_, Ram, Out>
+where
+    Ram: ?Sized,
+{
+    #[inline(always)]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<Ram, Out> Copy for Exec<'_, Ram, Out> where Ram: ?Sized {}
+
+type OpaqueExec<'tape, Ram, Ret> = unsafe fn(Addr<'tape>, Runner<'tape>, &mut Ram) -> Ret;
@@ -1,12 +1,15 @@
 //! Infrastructure to dump programs for debugging purposes.
 
+use crate::cpu::{Addr, DispatchToken};
 use crate::id::Id;
+use crate::tape::{AsClearedWriter, UnexpectedEndError, Writer};
 use crate::Offset;
 
 #[cfg(feature = "alloc")]
 use alloc::vec::Vec;
 use core::fmt::{self, Debug};
 use core::mem::{self, MaybeUninit};
+use core::ptr;
 
 #[cfg(feature = "macros")]
 pub use naam_macros::Dump;
@@ -24,9 +27,9 @@ pub trait Dump<'tape> {
 
 /// A dumper.
 #[derive(Clone, Copy)]
-#[repr(transparent)]
 pub struct Dumper<'tape> {
     tape: *const MaybeUninit<usize>,
+    entries: &'tape [Entry<'tape>],
     #[allow(dead_code)]
     id: Id<'tape>,
 }
@@ -37,6 +40,16 @@ impl<'tape> Dumper<'tape> {
     pub fn debug<'a, T: Dump<'tape>>(self, value: &'a T) -> DumpDebugBridge<'a, 'tape, T> {
         DumpDebugBridge(value, self)
     }
+
+    /// Takes a value and a function to dump it and return a bridge that can
+    /// be passed to methods expecting values that implement `Debug`.
+    pub fn debug_with<'a, T: ?Sized>(
+        self,
+        value: &'a T,
+        dump: DumpWithFn<'tape, T>,
+    ) -> DumpWithDebugBridge<'a, 'tape, T> {
+        DumpWithDebugBridge(value, dump, self)
+    }
 }
 
 /// A bridge to use dumpable values in `Debug`.
@@ -51,21 +64,72 @@ where
     }
 }
 
+/// A function dumping values of type `T`, used with `Dumper::debug_with`.
+pub type DumpWithFn<'tape, T> = fn(&T, &mut fmt::Formatter, Dumper<'tape>) -> fmt::Result;
+
+/// A bridge to use values dumped by a given function in `Debug`.
+pub struct DumpWithDebugBridge<'a, 'tape, T: ?Sized>(&'a T, DumpWithFn<'tape, T>, Dumper<'tape>);
+
+impl<T> Debug for DumpWithDebugBridge<'_, '_, T>
+where
+    T: ?Sized,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        (self.1)(self.0, fmt, self.2)
+    }
+}
+
+/// Offsets are dumped as the name of the label bound to them, if that was
+/// recorded, and as `[base + N]` otherwise.
 impl<'tape> Dump<'tape> for Offset<'tape> {
     fn dump(&self, fmt: &mut fmt::Formatter, dumper: Dumper<'tape>) -> fmt::Result {
-        write!(
-            fmt,
-            "{:?} /* {:p} */",
-            self,
-            (dumper.base() as *const u8).wrapping_add(self.value),
-        )
+        match dumper.label_at(self.value) {
+            Some(label) => fmt::Display::fmt(&label, fmt),
+            None => Debug::fmt(self, fmt),
+        }
     }
 }
 
-impl Dumper<'_> {
-    pub(crate) unsafe fn new(code: &[MaybeUninit<usize>]) -> Self {
+macro_rules! dump_as_debug {
+    ($($ty:ty),*) => {
+        $(
+            impl<'tape> Dump<'tape> for $ty {
+                #[inline(always)]
+                fn dump(&self, fmt: &mut fmt::Formatter, _dumper: Dumper<'tape>) -> fmt::Result {
+                    Debug::fmt(self, fmt)
+                }
+            }
+        )*
+    };
+}
+
+// Primitive types are dumped just like they are debugged, so that they can
+// be used as trailing data.
+dump_as_debug!(
+    (),
+    bool,
+    char,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64
+);
+
+impl<'tape> Dumper<'tape> {
+    unsafe fn new(code: &[MaybeUninit<usize>], entries: &'tape [Entry<'tape>]) -> Self {
         Self {
             tape: code.as_ptr(),
+            entries,
             id: Id::default(),
         }
     }
@@ -73,19 +137,166 @@ impl Dumper<'_> {
     pub(crate) fn base(&self) -> *const MaybeUninit<usize> {
         self.tape
     }
+
+    fn label_at(&self, offset: usize) -> Option<LabelName<'tape>> {
+        let position = find_entry(self.entries, offset, LABEL)?;
+        Some(unsafe { self.entries[position].payload.label })
+    }
+}
+
+/// The debug tape used by `Program::new`.
+///
+/// This is `Vec<MaybeUninit<usize>>` when the `alloc` feature is enabled,
+/// and `NoDebugTape` otherwise.
+#[cfg(feature = "alloc")]
+pub type DefaultDebugTape = Vec<MaybeUninit<usize>>;
+
+/// The debug tape used by `Program::new`.
+///
+/// This is `Vec<MaybeUninit<usize>>` when the `alloc` feature is enabled,
+/// and `NoDebugTape` otherwise.
+#[cfg(not(feature = "alloc"))]
+pub type DefaultDebugTape = NoDebugTape;
+
+/// A debug tape that doesn't record anything.
+///
+/// Programs built with it are dumped as `Tape(..)`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoDebugTape;
+
+impl AsRef<[MaybeUninit<usize>]> for NoDebugTape {
+    #[inline(always)]
+    fn as_ref(&self) -> &[MaybeUninit<usize>] {
+        &[]
+    }
+}
+
+unsafe impl AsClearedWriter for NoDebugTape {
+    #[inline(always)]
+    fn as_cleared_writer(&mut self) -> &mut dyn Writer {
+        self
+    }
+}
+
+unsafe impl Writer for NoDebugTape {
+    #[inline(always)]
+    fn word_offset(&self) -> usize {
+        0
+    }
+
+    #[inline(always)]
+    fn take(&mut self, _words: usize) -> Result<&mut [MaybeUninit<usize>], UnexpectedEndError> {
+        Err(UnexpectedEndError)
+    }
+
+    #[inline(always)]
+    fn written_mut(&mut self) -> &mut [MaybeUninit<usize>] {
+        &mut []
+    }
+}
+
+/// A location in the source code a program was compiled from.
+///
+/// Its `Display` implementation writes it as `file:line:column`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Span<'code> {
+    /// The name of the source file.
+    pub file: &'code str,
+    /// The line in the source file, usually starting at 1.
+    pub line: u32,
+    /// The column in the line, usually starting at 1.
+    pub column: u32,
+}
+
+impl fmt::Display for Span<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// The name of a label, as printed when dumping a program.
+///
+/// Its `Display` implementation writes the name itself.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum LabelName<'code> {
+    /// A label created by `Builder::label`, or the target of a branch that
+    /// was taken from `Builder::offset`, named `L0`, `L1` and so on in tape
+    /// order.
+    Auto(usize),
+    /// A label created by `Builder::named_label`.
+    Named(&'code str),
+}
+
+impl fmt::Display for LabelName<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Auto(index) => write!(fmt, "L{}", index),
+            Self::Named(name) => fmt.write_str(name),
+        }
+    }
 }
 
-#[derive(Default)]
-pub(crate) struct DebugInfo {
-    #[cfg(feature = "alloc")]
-    instructions: Vec<DebugInstruction>,
+/// Records debug info to a debug tape while a program is built.
+///
+/// Each instruction is recorded as an `Entry` with its size in bytes and the
+/// functions used to dump it and to report its successors. Its label and
+/// the source annotations that were current when it was emitted are recorded
+/// as entries of their own right before it, the annotations only when they
+/// changed since the previous instruction. When the debug tape is full, the
+/// remaining instructions are not recorded and the debug info is marked
+/// incomplete.
+///
+/// Automatic labels are only numbered by `DebugWriter::finish`, once all
+/// the branch targets are known.
+pub(crate) struct DebugWriter<'a> {
+    writer: &'a mut dyn Writer,
+    complete: bool,
+    span: Option<Span<'a>>,
+    symbol: Option<&'a str>,
+    written_span: Option<Span<'a>>,
+    written_symbol: Option<&'a str>,
+    label: Option<(usize, Option<&'a str>)>,
 }
 
-impl DebugInfo {
-    #[cfg(feature = "alloc")]
-    pub(crate) unsafe fn push<'tape, I>(&mut self, offset: usize)
+impl<'a> DebugWriter<'a> {
+    #[inline(always)]
+    pub(crate) fn new(writer: &'a mut dyn Writer) -> Self {
+        Self {
+            writer,
+            complete: true,
+            span: None,
+            symbol: None,
+            written_span: None,
+            written_symbol: None,
+            label: None,
+        }
+    }
+
+    /// Records that a label was bound at the given offset in words, so that
+    /// it names the operation pushed there. When several labels are bound at
+    /// the same offset, the first named one wins.
+    pub(crate) fn bind(&mut self, offset: usize, name: Option<&'a str>) {
+        if let Some((bound, bound_name)) = self.label {
+            if bound == offset && (bound_name.is_some() || name.is_none()) {
+                return;
+            }
+        }
+        self.label = Some((offset, name));
+    }
+
+    #[inline(always)]
+    pub(crate) fn set_span(&mut self, span: Option<Span<'a>>) {
+        self.span = span;
+    }
+
+    #[inline(always)]
+    pub(crate) fn set_symbol(&mut self, symbol: Option<&'a str>) {
+        self.symbol = symbol;
+    }
+
+    pub(crate) unsafe fn push<I>(&mut self, offset: usize, len: usize, successors: SuccessorsFn<'a>)
     where
-        I: Dump<'tape>,
+        I: Dump<'a>,
     {
         unsafe fn dump<'tape, I>(
             ptr: *const MaybeUninit<usize>,
@@ -98,41 +309,562 @@ impl DebugInfo {
             (&*(ptr as *const I)).dump(fmt, dumper)
         }
 
-        self.instructions
-            .push(DebugInstruction(offset, dump::<I> as *const ()));
+        if !self.complete {
+            return;
+        }
+        let span = (self.span != self.written_span).then(|| Entry {
+            key: key(offset, SPAN),
+            payload: Payload { span: self.span },
+        });
+        let symbol = (self.symbol != self.written_symbol).then(|| Entry {
+            key: key(offset, SYMBOL),
+            payload: Payload {
+                symbol: self.symbol,
+            },
+        });
+        let label = match self.label {
+            Some((bound, name)) if bound == offset => Some(Entry {
+                key: key(offset, LABEL),
+                payload: Payload {
+                    // Automatic labels are numbered by `finish`.
+                    label: name.map_or(LabelName::Auto(0), LabelName::Named),
+                },
+            }),
+            _ => None,
+        };
+        let instruction = Entry {
+            key: key(offset, INSTRUCTION),
+            payload: Payload {
+                instruction: InstructionEntry {
+                    size: len * WORD,
+                    dump: dump::<I>,
+                    successors,
+                },
+            },
+        };
+        let entries = [span, symbol, label, Some(instruction)];
+        let count = entries.iter().flatten().count();
+        match self.writer.take(count * ENTRY_WORDS) {
+            Ok(words) => {
+                let ptr = words.as_mut_ptr() as *mut Entry<'a>;
+                for (i, &entry) in entries.iter().flatten().enumerate() {
+                    ptr::write(ptr.add(i), entry);
+                }
+                self.written_span = self.span;
+                self.written_symbol = self.symbol;
+            }
+            Err(UnexpectedEndError) => self.complete = false,
+        }
     }
-}
 
-impl<'tape> Dump<'tape> for DebugInfo {
-    fn dump(
-        &self,
-        fmt: &mut fmt::Formatter,
-        #[cfg_attr(not(feature = "alloc"), allow(unused_variables))] dumper: Dumper<'tape>,
-    ) -> fmt::Result {
-        impl<'tape> Dump<'tape> for DebugInstruction {
-            fn dump(&self, fmt: &mut fmt::Formatter, dumper: Dumper<'tape>) -> fmt::Result {
-                // This is fine as long as DebugInfo and this type stay private and we
-                // they don't outlive the program they come from.
-                unsafe {
-                    let dump = mem::transmute::<
-                        _,
-                        unsafe fn(_, &mut fmt::Formatter, Dumper<'tape>) -> fmt::Result,
-                    >(self.1);
-                    dump(dumper.tape.add(self.0), fmt, dumper)?;
+    /// Labels the recorded operations that are branch targets, then numbers
+    /// the automatic labels in tape order.
+    ///
+    /// The labels of branch targets are inserted before them, so they are
+    /// left out if the debug tape has no room left for them.
+    ///
+    /// # Safety
+    ///
+    /// The recorded operations must have been written to the given tape,
+    /// with all their offsets resolved.
+    pub(crate) unsafe fn finish(&mut self, tape: &[MaybeUninit<usize>]) {
+        let entries = written_entries(self.writer);
+        let len = entries.len();
+        // Branch targets without a label are marked by setting the low bit
+        // of their size, which is otherwise a multiple of the word size.
+        let mut targets = 0;
+        for position in 0..len {
+            let entry = entries[position];
+            if entry.kind() != INSTRUCTION {
+                continue;
+            }
+            let ptr = tape.as_ptr().add(entry.word_offset());
+            (entry.payload.instruction.successors)(ptr, &mut |target| {
+                if find_entry(entries, target.value, LABEL).is_some() {
+                    return;
+                }
+                if let Some(target) = find_entry(entries, target.value, INSTRUCTION) {
+                    let size = &mut entries[target].payload.instruction.size;
+                    if *size & 1 == 0 {
+                        *size |= 1;
+                        targets += 1;
+                    }
+                }
+            });
+        }
+        let inserted = match self.writer.take(targets * ENTRY_WORDS) {
+            Ok(_) => targets,
+            Err(UnexpectedEndError) => 0,
+        };
+        // Entries are moved from the back, making room for the labels.
+        let entries = written_entries(self.writer);
+        let mut end = len + inserted;
+        for position in (0..len).rev() {
+            let mut entry = entries[position];
+            end -= 1;
+            if entry.kind() == INSTRUCTION && entry.payload.instruction.size & 1 != 0 {
+                entry.payload.instruction.size &= !1;
+                entries[end] = entry;
+                if inserted != 0 {
+                    end -= 1;
+                    entries[end] = Entry {
+                        key: key(entry.word_offset(), LABEL),
+                        payload: Payload {
+                            label: LabelName::Auto(0),
+                        },
+                    };
+                }
+            } else {
+                entries[end] = entry;
+            }
+        }
+        let mut next_label = 0;
+        for entry in entries {
+            if entry.kind() == LABEL {
+                if let LabelName::Auto(index) = &mut entry.payload.label {
+                    *index = next_label;
+                    next_label += 1;
                 }
-                Ok(())
             }
         }
+    }
+
+    #[inline(always)]
+    pub(crate) fn is_complete(&self) -> bool {
+        self.complete
+    }
+}
+
+/// The debug info of a program, as read from its debug tape.
+///
+/// This is returned by `Program::debug_info` and passed to CPUs through
+/// `Dispatch::dispatch_with_debug_info`.
+#[derive(Clone, Copy)]
+pub struct DebugInfo<'tape> {
+    complete: bool,
+    dumper: Dumper<'tape>,
+}
+
+impl<'tape> DebugInfo<'tape> {
+    /// Returns whether all the operations of the program were recorded.
+    ///
+    /// This is false when the debug tape was too small.
+    #[inline(always)]
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Returns an iterator over the recorded operations, in tape order.
+    #[inline(always)]
+    pub fn instructions(&self) -> Instructions<'tape> {
+        Instructions {
+            entries: self.entries().iter().enumerate(),
+            context: Context {
+                index: 0,
+                span: None,
+                symbol: None,
+            },
+            dumper: self.dumper,
+        }
+    }
+
+    /// Returns the recorded operation starting at the given offset, if any.
+    pub fn instruction_at(&self, offset: Offset<'tape>) -> Option<DebugInstruction<'tape>> {
+        let position = find_entry(self.entries(), offset.value, INSTRUCTION)?;
+        Some(self.instruction(position))
+    }
+
+    /// Returns the recorded operation whose bytes, including its trailing
+    /// data, contain the given byte offset, if any.
+    pub fn instruction_containing(&self, offset: usize) -> Option<DebugInstruction<'tape>> {
+        let entries = self.entries();
+        let end = match entries
+            .binary_search_by_key(&key(offset / WORD, INSTRUCTION), |entry| entry.key)
+        {
+            Ok(position) => position + 1,
+            Err(position) => position,
+        };
+        // Only the annotations of the next operation can come between the
+        // operation and the given offset.
+        let position = entries[..end]
+            .iter()
+            .rposition(|entry| entry.kind() == INSTRUCTION)?;
+        let instruction = self.instruction(position);
+        if offset - instruction.offset().value >= instruction.size() {
+            return None;
+        }
+        Some(instruction)
+    }
+
+    /// Returns the recorded operation starting at the given address, if any.
+    ///
+    /// This is useful to find out where in the source code a CPU halted or
+    /// panicked, through `DebugInstruction::span`.
+    #[inline(always)]
+    pub fn instruction_at_addr(&self, addr: Addr<'tape>) -> Option<DebugInstruction<'tape>> {
+        let value =
+            (addr.token as *const DispatchToken as usize).wrapping_sub(self.dumper.base() as usize);
+        self.instruction_at(Offset { value, id: addr.id })
+    }
+
+    /// Returns the recorded operation whose bytes, including its trailing
+    /// data, contain the given address, if any.
+    ///
+    /// See `DebugInfo::instruction_containing`.
+    #[inline(always)]
+    pub fn instruction_containing_addr(
+        &self,
+        addr: Addr<'tape>,
+    ) -> Option<DebugInstruction<'tape>> {
+        let offset =
+            (addr.token as *const DispatchToken as usize).wrapping_sub(self.dumper.base() as usize);
+        self.instruction_containing(offset)
+    }
 
+    #[inline(always)]
+    pub(crate) unsafe fn new(
+        tape: &'tape [MaybeUninit<usize>],
+        words: &'tape [MaybeUninit<usize>],
+        complete: bool,
+    ) -> Self {
+        let entries = core::slice::from_raw_parts(
+            words.as_ptr() as *const Entry<'tape>,
+            words.len() / ENTRY_WORDS,
+        );
+        Self {
+            complete,
+            dumper: Dumper::new(tape, entries),
+        }
+    }
+
+    #[inline(always)]
+    fn instruction(&self, position: usize) -> DebugInstruction<'tape> {
+        DebugInstruction {
+            position,
+            context: None,
+            dumper: self.dumper,
+        }
+    }
+
+    #[inline(always)]
+    fn entries(&self) -> &'tape [Entry<'tape>] {
+        self.dumper.entries
+    }
+}
+
+impl Debug for DebugInfo<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         let mut tuple = fmt.debug_tuple("Tape");
-        #[cfg(feature = "alloc")]
-        for instruction in &self.instructions {
-            tuple.field(&format_args!("{:?}", &dumper.debug(instruction)));
+        for instruction in self.instructions() {
+            tuple.field(&format_args!("{}", Line(instruction)));
+        }
+        if !self.complete {
+            tuple.field(&(..));
         }
-        #[cfg(not(feature = "alloc"))]
-        tuple.field(&(..));
         tuple.finish()
     }
 }
 
-struct DebugInstruction(usize, *const ());
+/// An iterator over the operations recorded in the debug info of a program.
+///
+/// This is returned by `DebugInfo::instructions`.
+#[derive(Clone)]
+pub struct Instructions<'tape> {
+    entries: core::iter::Enumerate<core::slice::Iter<'tape, Entry<'tape>>>,
+    context: Context<'tape>,
+    dumper: Dumper<'tape>,
+}
+
+impl<'tape> Iterator for Instructions<'tape> {
+    type Item = DebugInstruction<'tape>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (position, entry) = self.entries.next()?;
+            unsafe {
+                match entry.kind() {
+                    SPAN => self.context.span = entry.payload.span,
+                    SYMBOL => self.context.symbol = entry.payload.symbol,
+                    LABEL => {}
+                    _ => {
+                        let context = self.context;
+                        self.context.index += 1;
+                        return Some(DebugInstruction {
+                            position,
+                            context: Some(context),
+                            dumper: self.dumper,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.entries.size_hint().1)
+    }
+}
+
+impl Debug for Instructions<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_list().entries(self.clone()).finish()
+    }
+}
+
+/// An operation recorded in the debug info of a program.
+///
+/// Its `Debug` implementation dumps the operation.
+#[derive(Clone, Copy)]
+pub struct DebugInstruction<'tape> {
+    position: usize,
+    context: Option<Context<'tape>>,
+    dumper: Dumper<'tape>,
+}
+
+impl<'tape> DebugInstruction<'tape> {
+    /// Returns the offset of the operation.
+    #[inline(always)]
+    pub fn offset(&self) -> Offset<'tape> {
+        Offset {
+            value: self.entry().word_offset() * WORD,
+            id: Id::default(),
+        }
+    }
+
+    /// Returns the index of the operation among the recorded ones.
+    ///
+    /// The `Nop` operations used to pad the tape before over-aligned
+    /// operations are counted too. Unless the operation comes from
+    /// `DebugInfo::instructions`, this takes time linear in its index.
+    #[inline(always)]
+    pub fn index(&self) -> usize {
+        match self.context {
+            Some(context) => context.index,
+            None => self
+                .preceding()
+                .filter(|entry| entry.kind() == INSTRUCTION)
+                .count(),
+        }
+    }
+
+    /// Returns the size of the operation on tape in bytes, including its
+    /// trailing data if any.
+    #[inline(always)]
+    pub fn size(&self) -> usize {
+        unsafe { self.entry().payload.instruction.size }
+    }
+
+    /// Returns the name of the label bound to the offset of the operation,
+    /// if any.
+    #[inline(always)]
+    pub fn label(&self) -> Option<LabelName<'tape>> {
+        let previous = self.preceding().next_back()?;
+        if previous.key != key(self.entry().word_offset(), LABEL) {
+            return None;
+        }
+        Some(unsafe { previous.payload.label })
+    }
+
+    /// Returns the source span the operation was compiled from, if any.
+    ///
+    /// This is set with `Builder::set_span`. Spans are only recorded when
+    /// they change, so unless the operation comes from
+    /// `DebugInfo::instructions`, this takes time linear in the number of
+    /// operations since the span was last set.
+    #[inline(always)]
+    pub fn span(&self) -> Option<Span<'tape>> {
+        match self.context {
+            Some(context) => context.span,
+            None => self
+                .preceding()
+                .rfind(|entry| entry.kind() == SPAN)
+                .and_then(|entry| unsafe { entry.payload.span }),
+        }
+    }
+
+    /// Returns the symbol the operation belongs to, if any.
+    ///
+    /// This is set with `Builder::set_symbol`, and looked up like the span
+    /// of the operation.
+    #[inline(always)]
+    pub fn symbol(&self) -> Option<&'tape str> {
+        match self.context {
+            Some(context) => context.symbol,
+            None => self
+                .preceding()
+                .rfind(|entry| entry.kind() == SYMBOL)
+                .and_then(|entry| unsafe { entry.payload.symbol }),
+        }
+    }
+
+    /// Returns whether execution may continue with the next operation on
+    /// tape after this one, or `None` if the successors of the operation are
+    /// unknown, as its `Execute::as_branches` returns `None`.
+    #[inline(always)]
+    pub fn falls_through(&self) -> Option<bool> {
+        unsafe { (self.entry().payload.instruction.successors)(self.ptr(), &mut |_| {}) }
+    }
+
+    /// Calls the given function with each offset execution may continue
+    /// with after this operation, other than the next operation on tape.
+    ///
+    /// Operations whose successors are unknown report none.
+    #[inline(always)]
+    pub fn branches(&self, mut branch: impl FnMut(Offset<'tape>)) {
+        unsafe { (self.entry().payload.instruction.successors)(self.ptr(), &mut branch) };
+    }
+
+    #[inline(always)]
+    fn entry(&self) -> &'tape Entry<'tape> {
+        &self.dumper.entries[self.position]
+    }
+
+    /// Returns the entries recorded before the operation.
+    #[inline(always)]
+    fn preceding(&self) -> core::slice::Iter<'tape, Entry<'tape>> {
+        self.dumper.entries[..self.position].iter()
+    }
+
+    #[inline(always)]
+    fn ptr(&self) -> *const MaybeUninit<usize> {
+        self.dumper.base().wrapping_add(self.entry().word_offset())
+    }
+}
+
+impl Debug for DebugInstruction<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        // This is fine as long as DebugInfo doesn't outlive the program
+        // it comes from.
+        unsafe { (self.entry().payload.instruction.dump)(self.ptr(), fmt, self.dumper) }
+    }
+}
+
+/// The index and source annotations of an operation, as tracked while
+/// iterating over the debug tape.
+#[derive(Clone, Copy)]
+struct Context<'tape> {
+    index: usize,
+    span: Option<Span<'tape>>,
+    symbol: Option<&'tape str>,
+}
+
+/// An entry of the debug tape, as written by `DebugWriter::push`.
+///
+/// Its key is the offset in words of the operation it describes, shifted
+/// left to store its kind in the low bits, so that entries are sorted by
+/// key and the annotations of an operation come right before it.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Entry<'tape> {
+    key: usize,
+    payload: Payload<'tape>,
+}
+
+impl Entry<'_> {
+    #[inline(always)]
+    fn kind(&self) -> usize {
+        self.key & (KINDS - 1)
+    }
+
+    #[inline(always)]
+    fn word_offset(&self) -> usize {
+        self.key / KINDS
+    }
+}
+
+/// The payload of an entry, depending on its kind.
+#[derive(Clone, Copy)]
+#[repr(C)]
+union Payload<'tape> {
+    span: Option<Span<'tape>>,
+    symbol: Option<&'tape str>,
+    label: LabelName<'tape>,
+    instruction: InstructionEntry<'tape>,
+}
+
+/// The payload of an entry describing an operation.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct InstructionEntry<'tape> {
+    size: usize,
+    dump: DumpFn<'tape>,
+    successors: SuccessorsFn<'tape>,
+}
+
+/// The kinds of entries, in the order they are written for an operation.
+const SPAN: usize = 0;
+const SYMBOL: usize = 1;
+const LABEL: usize = 2;
+const INSTRUCTION: usize = 3;
+const KINDS: usize = 4;
+
+/// The number of words taken by each entry on the debug tape.
+const ENTRY_WORDS: usize = mem::size_of::<Entry>() / WORD;
+
+const WORD: usize = mem::size_of::<usize>();
+
+/// Returns the key of the entry of the given kind for the operation at the
+/// given offset in words.
+#[inline(always)]
+fn key(word_offset: usize, kind: usize) -> usize {
+    word_offset.wrapping_mul(KINDS) | kind
+}
+
+/// Returns the position of the entry of the given kind for the operation at
+/// the given offset in bytes, if any.
+fn find_entry(entries: &[Entry], offset: usize, kind: usize) -> Option<usize> {
+    if offset & (WORD - 1) != 0 {
+        return None;
+    }
+    entries
+        .binary_search_by_key(&key(offset / WORD, kind), |entry| entry.key)
+        .ok()
+}
+
+/// Returns the entries written to the debug tape so far.
+#[inline(always)]
+unsafe fn written_entries<'a, 'tape>(writer: &'a mut dyn Writer) -> &'a mut [Entry<'tape>] {
+    let words = writer.written_mut();
+    core::slice::from_raw_parts_mut(
+        words.as_mut_ptr() as *mut Entry<'tape>,
+        words.len() / ENTRY_WORDS,
+    )
+}
+
+/// An operation formatted as a line of a listing, prefixed by its offset and
+/// the name of its label if any, and followed by its source annotations.
+pub(crate) struct Line<'tape>(pub(crate) DebugInstruction<'tape>);
+
+impl fmt::Display for Line<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let instruction = &self.0;
+        write!(fmt, "{:?}", instruction.offset())?;
+        if let Some(label) = instruction.label() {
+            write!(fmt, " {}", label)?;
+        }
+        write!(fmt, ": {:?}{}", instruction, Annotations(instruction))
+    }
+}
+
+/// The source annotations of an operation, dumped as a trailing comment.
+struct Annotations<'a, 'tape>(&'a DebugInstruction<'tape>);
+
+impl fmt::Display for Annotations<'_, '_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match (self.0.symbol(), self.0.span()) {
+            (Some(symbol), Some(span)) => write!(fmt, " /* {} at {} */", symbol, span),
+            (Some(symbol), None) => write!(fmt, " /* {} */", symbol),
+            (None, Some(span)) => write!(fmt, " /* at {} */", span),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+type DumpFn<'tape> =
+    unsafe fn(*const MaybeUninit<usize>, &mut fmt::Formatter, Dumper<'tape>) -> fmt::Result;
+
+/// A function reporting the branches of an operation and returning whether
+/// it falls through, or returning `None` if its successors are unknown.
+pub(crate) type SuccessorsFn<'tape> =
+    unsafe fn(*const MaybeUninit<usize>, &mut dyn FnMut(Offset<'tape>)) -> Option<bool>;
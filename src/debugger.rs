@@ -0,0 +1,256 @@
+//! An interactive debugger running programs one operation at a time.
+
+use crate::builder::Build;
+use crate::cpu::{Reason, Step};
+use crate::debug_info::DebugInstruction;
+use crate::tape::AsClearedWriter;
+use crate::{Offset, Program, Runner};
+
+#[cfg(feature = "alloc")]
+use alloc::vec;
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem;
+use core::ops::Deref;
+use stable_deref_trait::StableDeref;
+
+/// A debugger, which runs a program one operation at a time.
+///
+/// Offsets are in bytes, as printed by the `Debug` implementation of
+/// `Offset` and returned by its conversion to `usize`.
+///
+/// Breakpoints are stored one per word of the tape, so `Breakpoints` should
+/// be at least as long as the tape, typically `Vec<bool>` or `[bool; N]`.
+///
+/// The RAM is passed to each call, so it can be inspected and modified
+/// between two steps.
+///
+/// This only supports CPUs that implement `Step`.
+pub struct Debugger<'program, Cpu, Tape, Code, DebugTape, Breakpoints> {
+    program: &'program Program<Cpu, Tape, Code, DebugTape>,
+    breakpoints: Breakpoints,
+    offset: Option<usize>,
+}
+
+#[cfg(feature = "alloc")]
+impl<'program, Cpu, Tape, Code, DebugTape> Debugger<'program, Cpu, Tape, Code, DebugTape, Vec<bool>>
+where
+    Tape: AsClearedWriter,
+{
+    /// Returns a new debugger for the given program, stopped before its
+    /// first operation and without any breakpoint.
+    pub fn new(program: &'program Program<Cpu, Tape, Code, DebugTape>) -> Self {
+        let breakpoints = vec![false; program.tape.written().len()];
+        Self::with_breakpoints(program, breakpoints)
+    }
+}
+
+impl<'program, Cpu, Tape, Code, DebugTape, Breakpoints>
+    Debugger<'program, Cpu, Tape, Code, DebugTape, Breakpoints>
+where
+    Breakpoints: AsRef<[bool]> + AsMut<[bool]>,
+{
+    /// Returns a new debugger for the given program, stopped before its
+    /// first operation, storing breakpoints in the given storage.
+    ///
+    /// Breakpoints already set in the storage are kept.
+    #[inline(always)]
+    pub fn with_breakpoints(
+        program: &'program Program<Cpu, Tape, Code, DebugTape>,
+        breakpoints: Breakpoints,
+    ) -> Self {
+        Self {
+            program,
+            breakpoints,
+            offset: Some(0),
+        }
+    }
+
+    /// Gets a reference to the program being debugged.
+    #[inline(always)]
+    pub fn program(&self) -> &'program Program<Cpu, Tape, Code, DebugTape> {
+        self.program
+    }
+
+    /// Returns the offset of the next operation to execute, or `None` if
+    /// the program halted.
+    #[inline(always)]
+    pub fn offset(&self) -> Option<usize> {
+        self.offset
+    }
+
+    /// Sets a breakpoint at the given offset.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if the offset is beyond the breakpoint storage.
+    pub fn set_breakpoint(&mut self, offset: usize) {
+        match self.breakpoints.as_mut().get_mut(Self::index(offset)) {
+            Some(breakpoint) => *breakpoint = true,
+            None => panic!("breakpoint out of bounds"),
+        }
+    }
+
+    /// Clears the breakpoint at the given offset, if any.
+    pub fn clear_breakpoint(&mut self, offset: usize) {
+        if let Some(breakpoint) = self.breakpoints.as_mut().get_mut(Self::index(offset)) {
+            *breakpoint = false;
+        }
+    }
+
+    /// Returns whether there is a breakpoint at the given offset.
+    pub fn has_breakpoint(&self, offset: usize) -> bool {
+        self.breakpoints
+            .as_ref()
+            .get(Self::index(offset))
+            .copied()
+            .unwrap_or(false)
+    }
+
+    /// Stops the debugger before the first operation of the program again.
+    #[inline(always)]
+    pub fn restart(&mut self) {
+        self.offset = Some(0);
+    }
+
+    #[inline(always)]
+    fn index(offset: usize) -> usize {
+        // Offsets that aren't a multiple of the word size can't be the start
+        // of an operation, so they are mapped out of bounds.
+        if offset & (mem::size_of::<usize>() - 1) != 0 {
+            return usize::MAX;
+        }
+        offset / mem::size_of::<usize>()
+    }
+}
+
+impl<'program, Cpu, Tape, Code, DebugTape, Breakpoints>
+    Debugger<'program, Cpu, Tape, Code, DebugTape, Breakpoints>
+where
+    Tape: AsClearedWriter,
+    DebugTape: AsClearedWriter,
+{
+    /// Returns the next operation to execute, so that it can be dumped.
+    ///
+    /// This is `None` if the program halted, or if the operation wasn't
+    /// recorded in the debug info of the program.
+    pub fn instruction(&self) -> Option<DebugInstruction<'program>> {
+        let offset = self.offset?;
+        let debug_info = self.program.debug_info();
+        debug_info.instruction_at(Offset {
+            value: offset,
+            id: Default::default(),
+        })
+    }
+}
+
+impl<'program, Cpu, Tape, Code, DebugTape, Breakpoints>
+    Debugger<'program, Cpu, Tape, Code, DebugTape, Breakpoints>
+where
+    Cpu: Step<
+        <<Code as Deref>::Target as Build<Cpu>>::Ram,
+        <<Code as Deref>::Target as Build<Cpu>>::Output,
+    >,
+    Tape: AsClearedWriter,
+    Code: StableDeref,
+    <Code as Deref>::Target: Build<Cpu>,
+    DebugTape: AsClearedWriter,
+    Breakpoints: AsRef<[bool]> + AsMut<[bool]>,
+{
+    /// Executes the next operation with some RAM.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if the program already halted.
+    pub fn step(
+        &mut self,
+        ram: &mut <<Code as Deref>::Target as Build<Cpu>>::Ram,
+    ) -> Event<<<Code as Deref>::Target as Build<Cpu>>::Output> {
+        let offset = match self.offset {
+            Some(offset) => offset,
+            None => panic!("program already halted"),
+        };
+        let runner = Runner::new(self.program.tape.written(), self.program.build);
+        let addr = runner.resolve_offset(Offset {
+            value: offset,
+            id: runner.id,
+        });
+        match unsafe { self.program.cpu.step(addr, runner, ram) } {
+            Ok(next) => {
+                self.offset = Some(runner.offset_of(next).value);
+                Event::Stepped
+            }
+            Err(halt) => match halt.reason {
+                Reason::Halted(value) => {
+                    self.offset = None;
+                    Event::Halted(value)
+                }
+                Reason::Paused(next) => {
+                    self.offset = Some(runner.offset_of(next).value);
+                    Event::Paused
+                }
+            },
+        }
+    }
+
+    /// Executes operations with some RAM until the program reaches a
+    /// breakpoint, halts or pauses.
+    ///
+    /// The next operation is always executed, even if there is a breakpoint
+    /// at its offset, so that calling this method again continues past it.
+    ///
+    /// # Panics
+    ///
+    /// See `Debugger::step`.
+    pub fn run(
+        &mut self,
+        ram: &mut <<Code as Deref>::Target as Build<Cpu>>::Ram,
+    ) -> Event<<<Code as Deref>::Target as Build<Cpu>>::Output> {
+        loop {
+            match self.step(ram) {
+                Event::Stepped => {}
+                event => return event,
+            }
+            if let Some(offset) = self.offset {
+                if self.has_breakpoint(offset) {
+                    return Event::Breakpoint;
+                }
+            }
+        }
+    }
+}
+
+impl<Cpu, Tape, Code, DebugTape, Breakpoints> fmt::Debug
+    for Debugger<'_, Cpu, Tape, Code, DebugTape, Breakpoints>
+where
+    Tape: AsClearedWriter,
+    DebugTape: AsClearedWriter,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let mut tuple = fmt.debug_tuple("Debugger");
+        match (self.offset, self.instruction()) {
+            (Some(offset), Some(instruction)) => {
+                tuple.field(&format_args!("[base + {}]: {:?}", offset, instruction))
+            }
+            (Some(offset), None) => tuple.field(&format_args!("[base + {}]", offset)),
+            (None, _) => tuple.field(&format_args!("Halted")),
+        };
+        tuple.finish()
+    }
+}
+
+/// What happened when the debugger executed operations.
+#[derive(Clone, Copy, Debug)]
+#[must_use]
+pub enum Event<Out = ()> {
+    /// A single operation was executed.
+    Stepped,
+    /// The program reached a breakpoint, and stopped before executing the
+    /// operation there.
+    Breakpoint,
+    /// The program paused, and executing operations resumes it.
+    Paused,
+    /// The program halted with the given value.
+    Halted(Out),
+}
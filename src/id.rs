@@ -1,6 +1,50 @@
 use core::marker::PhantomData;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 #[derive(Clone, Copy, Default)]
 pub(crate) struct Id<'id> {
-    marker: PhantomData<*mut &'id ()>,
+    marker: PhantomData<fn(&'id ()) -> &'id ()>,
+}
+
+/// The identity of a built program, which is never reused by another one,
+/// unlike the address of its tape.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct BuildId(usize);
+
+impl BuildId {
+    /// Returns a new build id.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if all build ids were already used, so that
+    /// none of them is ever reused.
+    pub(crate) fn new() -> Self {
+        match next_id() {
+            Some(id) => Self(id),
+            None => panic!("ran out of build ids"),
+        }
+    }
+}
+
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// Takes the next build id, if any is left.
+#[cfg(target_has_atomic = "ptr")]
+#[inline(always)]
+fn next_id() -> Option<usize> {
+    NEXT.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |id| id.checked_add(1))
+        .ok()
+}
+
+/// Takes the next build id, if any is left.
+///
+/// Targets without compare-and-swap only provide atomic loads and stores,
+/// so programs built concurrently, for example from an interrupt handler,
+/// may be given the same id there.
+#[cfg(not(target_has_atomic = "ptr"))]
+#[inline(always)]
+fn next_id() -> Option<usize> {
+    let id = NEXT.load(Ordering::Relaxed);
+    NEXT.store(id.checked_add(1)?, Ordering::Relaxed);
+    Some(id)
 }
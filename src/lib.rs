@@ -10,72 +10,142 @@ extern crate alloc;
 
 pub mod builder;
 pub mod builtins;
+pub mod cfg;
 pub mod cpu;
 pub mod debug_info;
+pub mod debugger;
 mod id;
 pub mod tape;
 
-use crate::builder::{Build, Builder, Instruction};
+#[cfg(feature = "macros")]
+pub use naam_macros::{asm, instruction_set};
+
+use crate::builder::{Build, BuildError, Builder, Instruction};
 use crate::builtins::Unreachable;
-use crate::cpu::{Addr, Dispatch, Halt};
-use crate::debug_info::{DebugInfo, Dump, Dumper};
-use crate::id::Id;
+use crate::cfg::{self as control_flow, Branches};
+use crate::cpu::{Addr, Dispatch, DispatchToken, Halt, Reason, ThreadSafe};
+use crate::debug_info::{DebugInfo, DefaultDebugTape, Dump};
+use crate::id::{BuildId, Id};
 use crate::tape::AsClearedWriter;
 
 use core::fmt::{self, Debug};
 use core::marker::PhantomData as marker;
 use core::mem::{self, MaybeUninit};
 use core::ops::Deref;
+use core::ptr;
 use stable_deref_trait::StableDeref;
 
 /// A compiled program.
-pub struct Program<Cpu, Tape, Code> {
+///
+/// The debug tape records which operations are on the tape, so that the
+/// program can be dumped through its `Debug` implementation.
+///
+/// Programs are neither `Send` nor `Sync`, as nothing is known about the
+/// operations on their tape, unless they are built with the `ThreadSafe` CPU,
+/// which only supports operations that are both.
+///
+/// Programs with over-aligned operations must be built with a tape that
+/// guarantees their alignment, such as `Vec<MaybeUninit<usize>>` or
+/// `FixedTape` backed by `AlignedWords`.
+pub struct Program<Cpu, Tape, Code, DebugTape = DefaultDebugTape> {
     cpu: Cpu,
     tape: Tape,
-    debug_info: DebugInfo,
+    debug_tape: DebugTape,
+    debug_complete: bool,
+    build: BuildId,
     code: Code,
     not_sync: marker<*mut ()>,
 }
 
 impl<Cpu, Tape, Code> Program<Cpu, Tape, Code>
 where
-    Cpu: for<'ram> Dispatch<<<Code as Deref>::Target as Build<Cpu>>::Ram>,
+    Cpu: Dispatch<
+        <<Code as Deref>::Target as Build<Cpu>>::Ram,
+        <<Code as Deref>::Target as Build<Cpu>>::Output,
+    >,
     Tape: AsClearedWriter,
     Code: StableDeref,
     <Code as Deref>::Target: Build<Cpu>,
 {
     /// Returns a new program built from the given code.
     pub fn new(
+        cpu: Cpu,
+        tape: Tape,
+        code: Code,
+    ) -> Result<Self, BuildError<<<Code as Deref>::Target as Build<Cpu>>::Error>> {
+        Self::with_debug_tape(cpu, tape, DefaultDebugTape::default(), code)
+    }
+}
+
+impl<Cpu, Tape, Code, DebugTape> Program<Cpu, Tape, Code, DebugTape>
+where
+    Cpu: Dispatch<
+        <<Code as Deref>::Target as Build<Cpu>>::Ram,
+        <<Code as Deref>::Target as Build<Cpu>>::Output,
+    >,
+    Tape: AsClearedWriter,
+    Code: StableDeref,
+    <Code as Deref>::Target: Build<Cpu>,
+    DebugTape: AsClearedWriter,
+{
+    /// Returns a new program built from the given code, recording its debug
+    /// info to the given debug tape.
+    ///
+    /// If the debug tape is too small, the program is still built, but only
+    /// the operations that fit in it are dumped.
+    ///
+    /// This method returns `BuildError::UnboundLabel` if a label was never
+    /// bound while building the program.
+    pub fn with_debug_tape(
         cpu: Cpu,
         mut tape: Tape,
+        mut debug_tape: DebugTape,
         code: Code,
-    ) -> Result<Program<Cpu, Tape, Code>, <<Code as Deref>::Target as Build<Cpu>>::Error> {
-        let mut builder = Builder::new(cpu, &mut tape);
+    ) -> Result<Self, BuildError<<<Code as Deref>::Target as Build<Cpu>>::Error>> {
+        let mut builder = Builder::new(cpu, &mut tape, &mut debug_tape);
         code.build(&mut builder)?;
-        builder.emit(Unreachable)?;
-        unsafe {
-            let debug_info = builder.into_debug_info();
-            Ok(Self {
-                cpu,
-                tape,
-                debug_info,
-                code,
-                not_sync: marker,
-            })
-        }
+        builder.set_span(None);
+        builder.set_symbol(None);
+        builder
+            .emit(Unreachable)
+            .map_err(|error| BuildError::Build(error.into()))?;
+        let debug_complete = builder.finish()?;
+        Ok(Self {
+            cpu,
+            tape,
+            debug_tape,
+            debug_complete,
+            build: BuildId::new(),
+            code,
+            not_sync: marker,
+        })
     }
 
-    /// Runs the program with some RAM.
-    pub fn run(&self, ram: &mut <<Code as Deref>::Target as Build<Cpu>>::Ram) {
-        let tape = self.tape.as_ref();
-        unsafe {
-            let runner = Runner::new(tape);
-            let addr = Addr {
-                token: &*(tape.as_ptr() as *const _),
-                id: runner.id,
-            };
-            self.cpu.dispatch(addr, runner, ram)
+    /// Runs the program with some RAM, returning the value it halted with,
+    /// or a continuation if it paused, for example because a `Metered` CPU
+    /// ran out of fuel.
+    pub fn run(
+        &self,
+        ram: &mut <<Code as Deref>::Target as Build<Cpu>>::Ram,
+    ) -> Result<<<Code as Deref>::Target as Build<Cpu>>::Output, Continuation<'_>> {
+        unsafe { self.dispatch(0, ram) }
+    }
+
+    /// Resumes a paused program with some RAM, until it either halts or
+    /// pauses again.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if the continuation comes from another program.
+    pub fn resume(
+        &self,
+        continuation: Continuation<'_>,
+        ram: &mut <<Code as Deref>::Target as Build<Cpu>>::Ram,
+    ) -> Result<<<Code as Deref>::Target as Build<Cpu>>::Output, Continuation<'_>> {
+        if continuation.build != self.build {
+            panic!("continuation comes from another program");
         }
+        unsafe { self.dispatch(continuation.offset, ram) }
     }
 
     /// Gets a reference to the code used by the program.
@@ -83,29 +153,134 @@ where
     pub fn code(&self) -> &Code {
         &self.code
     }
+
+    unsafe fn dispatch(
+        &self,
+        offset: usize,
+        ram: &mut <<Code as Deref>::Target as Build<Cpu>>::Ram,
+    ) -> Result<<<Code as Deref>::Target as Build<Cpu>>::Output, Continuation<'_>> {
+        let tape = self.tape.written();
+        let debug_info = self.debug_info();
+        let runner = Runner::new(tape, self.build);
+        let addr = runner.resolve_offset(Offset {
+            value: offset,
+            id: runner.id,
+        });
+        match self
+            .cpu
+            .dispatch_with_debug_info(addr, runner, ram, debug_info)
+            .reason
+        {
+            Reason::Halted(value) => Ok(value),
+            Reason::Paused(addr) => Err(Continuation {
+                offset: runner.offset_of(addr).value,
+                build: self.build,
+                marker,
+            }),
+        }
+    }
+}
+
+// Programs built with `ThreadSafe` only contain operations that are `Send`
+// and `Sync`, and running them only ever mutates the RAM they are given.
+unsafe impl<Cpu, Tape, Code, DebugTape> Send for Program<ThreadSafe<Cpu>, Tape, Code, DebugTape>
+where
+    Cpu: Send,
+    Tape: Send,
+    Code: Send,
+    DebugTape: Send,
+{
 }
 
-impl<Cpu, Tape, Code> fmt::Debug for Program<Cpu, Tape, Code>
+unsafe impl<Cpu, Tape, Code, DebugTape> Sync for Program<ThreadSafe<Cpu>, Tape, Code, DebugTape>
+where
+    Cpu: Sync,
+    Tape: Sync,
+    Code: Sync,
+    DebugTape: Sync,
+{
+}
+
+impl<Cpu, Tape, Code, DebugTape> Program<Cpu, Tape, Code, DebugTape>
+where
+    Tape: AsClearedWriter,
+    DebugTape: AsClearedWriter,
+{
+    /// Returns the debug info of the program.
+    ///
+    /// It can be used to iterate over the operations of the program and to
+    /// look them up by offset.
+    pub fn debug_info(&self) -> DebugInfo<'_> {
+        let tape = self.tape.written();
+        unsafe { DebugInfo::new(tape, self.debug_tape.written(), self.debug_complete) }
+    }
+
+    /// Writes the control-flow graph of the program in Graphviz's DOT
+    /// format.
+    ///
+    /// Each node is a basic block, listing its operations as they are
+    /// dumped, and edges come from `Branches`. Only the operations recorded
+    /// in the debug info are part of the graph.
+    pub fn write_dot(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        control_flow::write_dot(self.debug_info(), out)
+    }
+}
+
+/// A paused program, which can be resumed where it stopped with
+/// `Program::resume`.
+///
+/// Continuations only ever resume the program they come from, which is
+/// identified by a unique id assigned when it was built.
+pub struct Continuation<'program> {
+    offset: usize,
+    build: BuildId,
+    marker: marker<&'program ()>,
+}
+
+impl Debug for Continuation<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "Continuation([base + {}])", self.offset)
+    }
+}
+
+impl<Cpu, Tape, Code, DebugTape> fmt::Debug for Program<Cpu, Tape, Code, DebugTape>
 where
     Cpu: Debug,
     Code: Debug,
-    Tape: AsRef<[MaybeUninit<usize>]>,
+    Tape: AsClearedWriter,
+    DebugTape: AsClearedWriter,
 {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        let dumper = unsafe { Dumper::new(self.tape.as_ref()) };
         fmt.debug_struct("Machine")
             .field("cpu", &self.cpu)
             .field("code", &self.code)
-            .field("tape", &dumper.debug(&self.debug_info))
+            .field("tape", &self.debug_info())
             .finish()
     }
 }
 
 /// How to execute an operation, the main piece of code for end users.
-pub trait Execute<'tape, Ram>: 'tape + Copy + Dump<'tape> + Sized
+pub trait Execute<'tape, Ram, Out = ()>: 'tape + Copy + Dump<'tape> + Sized
 where
     Ram: ?Sized,
 {
+    /// Whether the operation is followed on tape by trailing data.
+    ///
+    /// Operations setting this to `true` must implement `Trailing` and can
+    /// only be emitted with `Builder::emit_with_trailing`.
+    const TRAILING: bool = false;
+
+    /// Returns the operation as `Branches`, so that the offsets it may
+    /// continue with are recorded in the debug info.
+    ///
+    /// This returns `None` by default, in which case the successors of the
+    /// operation are unknown. Operations implementing `Branches`, usually
+    /// by deriving it, opt in by returning `Some(self)`.
+    #[inline(always)]
+    fn as_branches(&self) -> Option<&dyn Branches<'tape>> {
+        None
+    }
+
     /// Executes the operation.
     ///
     /// Operations are free to mutate both the RAM and the environment provided
@@ -117,7 +292,35 @@ where
     /// **Note:** As the CPU is the entity responsible for dispatching
     /// operations and most CPUs wrap calls to that function in a separate
     /// unsafe function, users should probably mark this method as inline.
-    fn execute(pc: Pc<'tape, Self>, runner: Runner<'tape>, ram: &mut Ram) -> Destination<'tape>;
+    fn execute(
+        pc: Pc<'tape, Self>,
+        runner: Runner<'tape>,
+        ram: &mut Ram,
+    ) -> Destination<'tape, Out>;
+}
+
+/// Operations followed on tape by a variable number of values, such as
+/// argument lists or jump tables.
+///
+/// The trailing values of an operation are accessed through `Pc::trailing`.
+pub trait Trailing<'tape>: 'tape {
+    /// The type of the trailing values.
+    ///
+    /// CPUs may only support some types of trailing values, through
+    /// `AcceptTrailing`. For example, `ThreadSafe` requires them to be `Send`
+    /// and `Sync`, as they are shared along with their program.
+    type Item: 'tape + Copy + Dump<'tape>;
+
+    /// Returns a trailing value as `Branches`, so that the offsets it holds
+    /// are recorded as successors of the operation, if it reports its own
+    /// through `Execute::as_branches`.
+    ///
+    /// This returns `None` by default, for values that hold no offsets.
+    #[inline(always)]
+    fn item_as_branches(item: &Self::Item) -> Option<&dyn Branches<'tape>> {
+        let _ = item;
+        None
+    }
 }
 
 /// The runner, which allows resolving tape offsets during execution.
@@ -126,6 +329,7 @@ pub struct Runner<'tape> {
     tape: *const u8,
     #[cfg(debug_assertions)]
     len: usize,
+    build: BuildId,
     id: Id<'tape>,
 }
 
@@ -134,7 +338,7 @@ impl<'tape> Runner<'tape> {
     #[inline(always)]
     pub fn resolve_offset(self, offset: Offset<'tape>) -> Addr<'tape> {
         debug_assert!(offset.value < self.len);
-        debug_assert!(offset.value % mem::align_of::<usize>() == 0);
+        debug_assert!(offset.value & (mem::align_of::<usize>() - 1) == 0);
         unsafe {
             let byte = self.tape.add(offset.value);
             Addr {
@@ -144,18 +348,48 @@ impl<'tape> Runner<'tape> {
         }
     }
 
+    /// Returns the tape offset of a physical address.
+    #[inline(always)]
+    pub fn offset_of(self, addr: Addr<'tape>) -> Offset<'tape> {
+        Offset {
+            value: (addr.token as *const DispatchToken as usize).wrapping_sub(self.tape as usize),
+            id: addr.id,
+        }
+    }
+
     /// Returns the error token to return from the program altogether.
     #[inline(always)]
     pub fn halt(self) -> Halt<'tape> {
-        Halt { id: self.id }
+        self.halt_with(())
     }
 
+    /// Returns the error token to return from the program altogether with
+    /// the given value, which is then returned by `Program::run`.
     #[inline(always)]
-    fn new(tape: &'tape [MaybeUninit<usize>]) -> Self {
+    pub fn halt_with<Out>(self, value: Out) -> Halt<'tape, Out> {
+        Halt {
+            reason: Reason::Halted(value),
+            id: self.id,
+        }
+    }
+
+    /// Returns the error token to pause the program, which can then be
+    /// resumed at the given address.
+    #[inline(always)]
+    pub fn pause<Out>(self, addr: Addr<'tape>) -> Halt<'tape, Out> {
+        Halt {
+            reason: Reason::Paused(addr),
+            id: self.id,
+        }
+    }
+
+    #[inline(always)]
+    fn new(tape: &'tape [MaybeUninit<usize>], build: BuildId) -> Self {
         Self {
             tape: tape.as_ptr() as *const u8,
             #[cfg(debug_assertions)]
             len: tape.len().wrapping_mul(mem::size_of::<usize>()),
+            build,
             id: Id::default(),
         }
     }
@@ -165,9 +399,9 @@ impl<'tape> Runner<'tape> {
 ///
 /// This represents the current position of the CPU in the program and
 /// lets the user access the contents of the operation currently executed.
-#[repr(transparent)]
 pub struct Pc<'tape, Op> {
     instruction: &'tape Instruction<Op>,
+    next: *const DispatchToken,
     id: Id<'tape>,
 }
 
@@ -182,24 +416,55 @@ impl<'tape, Op> Pc<'tape, Op> {
     }
 
     /// Returns the physical address of the next operation in the program.
+    ///
+    /// This skips past the trailing data of the current operation, if any.
     #[inline(always)]
     pub fn next(self) -> Addr<'tape> {
-        unsafe {
-            let end = (self.instruction as *const Instruction<Op>).add(1);
-            Addr {
-                token: &*(end as *const _),
-                id: self.id,
-            }
+        Addr {
+            token: unsafe { &*self.next },
+            id: self.id,
         }
     }
 
+    /// Returns the values stored on tape after the operation currently
+    /// executed.
+    ///
+    /// This is empty if the operation wasn't emitted with
+    /// `Builder::emit_with_trailing`.
+    #[inline(always)]
+    pub fn trailing(self) -> &'tape [Op::Item]
+    where
+        Op: Trailing<'tape>,
+    {
+        let end = self.instruction.end();
+        if ptr::eq(self.next, end) {
+            return &[];
+        }
+        unsafe { self.instruction.trailing() }
+    }
+
     /// Creates a new program counter out of a physical address.
     ///
     /// This is only useful for CPU (remember, virtual ones) designers.
+    ///
+    /// # Safety
+    ///
+    /// The address must point to an operation of type `Op`.
     #[inline(always)]
-    pub unsafe fn from_addr(addr: Addr<'tape>) -> Self {
+    pub unsafe fn from_addr<Ram, Out>(addr: Addr<'tape>) -> Self
+    where
+        Op: Execute<'tape, Ram, Out>,
+        Ram: ?Sized,
+    {
+        let instruction = &*(addr.token as *const _ as *const Instruction<Op>);
+        let next = if Op::TRAILING {
+            instruction.end_of_trailing()
+        } else {
+            instruction.end()
+        };
         Self {
-            instruction: &*(addr.token as *const _ as *const _),
+            instruction,
+            next,
             id: addr.id,
         }
     }
@@ -227,7 +492,7 @@ impl<'tape, Op> Deref for Pc<'tape, Op> {
 ///
 /// This type alias only exists so that simple programs need only one import
 /// instead of two.
-pub type Destination<'tape> = Result<Addr<'tape>, Halt<'tape>>;
+pub type Destination<'tape, Out = ()> = Result<Addr<'tape>, Halt<'tape, Out>>;
 
 /// A tape offset.
 ///
@@ -1,11 +1,19 @@
 //! Tapes to which programs are written.
 //!
 //! `Vec<MaybeUninit<usize>>` implements both `AsClearedWriter` and `Writer`
-//! when the `std` feature is enabled.
+//! when the `alloc` feature is enabled. Without it, `FixedTape` can be used
+//! to write programs to a mutable slice or an array.
+//!
+//! Operations whose alignment exceeds `usize`'s can only be written to tapes
+//! that guarantee that alignment. `Vec<MaybeUninit<usize>>` starts its tape
+//! at the first word of its buffer aligned to 64 bytes, like `AlignedWords`,
+//! which can back a `FixedTape`.
 
 #[cfg(feature = "alloc")]
 use alloc::vec::Vec;
-use core::mem::MaybeUninit;
+use core::mem::{self, MaybeUninit};
+#[cfg(feature = "alloc")]
+use core::{ptr, slice};
 
 /// Types from which a cleared writer can be obtained.
 ///
@@ -15,9 +23,28 @@ use core::mem::MaybeUninit;
 /// must respect various invariants that I'm too lazy to list right now,
 /// but more or less it just represents a glorified slice that can be
 /// made longer.
+///
+/// The tape must always start at an address aligned to `ALIGN`, even after
+/// the value representing it moved.
 pub unsafe trait AsClearedWriter: AsRef<[MaybeUninit<usize>]> {
+    /// The alignment of the start of the tape.
+    ///
+    /// Over-aligned operations are aligned relative to the start of the
+    /// tape, so they can only be written to it if their alignment doesn't
+    /// exceed this one.
+    const ALIGN: usize = mem::align_of::<usize>();
+
     /// Returns a cleared writer from this value.
     fn as_cleared_writer(&mut self) -> &mut dyn Writer;
+
+    /// Returns the words written to the tape.
+    ///
+    /// These are the words returned by `as_ref`, unless the tape doesn't
+    /// start at the beginning of the value, as with `Vec<MaybeUninit<usize>>`.
+    #[inline(always)]
+    fn written(&self) -> &[MaybeUninit<usize>] {
+        self.as_ref()
+    }
 }
 
 /// Types that can be written into.
@@ -31,36 +58,219 @@ pub unsafe trait Writer {
 
     /// Take `n` words from the writer, starting at the current position.
     fn take(&mut self, n: usize) -> Result<&mut [MaybeUninit<usize>], UnexpectedEndError>;
+
+    /// Returns the words written so far, so that they can be patched.
+    fn written_mut(&mut self) -> &mut [MaybeUninit<usize>];
 }
 
 /// An error that signals that the end of the tape was unexpectedly reached.
 #[derive(Clone, Copy, Debug)]
 pub struct UnexpectedEndError;
 
+/// A tape of fixed capacity.
+///
+/// `Words` is typically either `&mut [MaybeUninit<usize>]`,
+/// `[MaybeUninit<usize>; N]` or `AlignedWords<N>`. Writing more words than
+/// fit in it returns `UnexpectedEndError`.
+pub struct FixedTape<Words> {
+    words: Words,
+    len: usize,
+}
+
+impl<Words> FixedTape<Words>
+where
+    Words: AsRef<[MaybeUninit<usize>]> + AsMut<[MaybeUninit<usize>]>,
+{
+    /// Returns a new empty tape, backed by the given words.
+    #[inline(always)]
+    pub fn new(words: Words) -> Self {
+        Self { words, len: 0 }
+    }
+
+    /// Returns the maximum number of words that can be written to the tape.
+    #[inline(always)]
+    pub fn capacity(&self) -> usize {
+        self.words.as_ref().len()
+    }
+}
+
+impl<const N: usize> Default for FixedTape<[MaybeUninit<usize>; N]> {
+    #[inline(always)]
+    fn default() -> Self {
+        Self::new([MaybeUninit::uninit(); N])
+    }
+}
+
+impl<const N: usize> Default for FixedTape<AlignedWords<N>> {
+    #[inline(always)]
+    fn default() -> Self {
+        Self::new(AlignedWords([MaybeUninit::uninit(); N]))
+    }
+}
+
+impl<Words> AsRef<[MaybeUninit<usize>]> for FixedTape<Words>
+where
+    Words: AsRef<[MaybeUninit<usize>]>,
+{
+    #[inline(always)]
+    fn as_ref(&self) -> &[MaybeUninit<usize>] {
+        &self.words.as_ref()[..self.len]
+    }
+}
+
+unsafe impl<Words> AsClearedWriter for FixedTape<Words>
+where
+    Words: Storage,
+{
+    const ALIGN: usize = Words::ALIGN;
+
+    #[inline(always)]
+    fn as_cleared_writer(&mut self) -> &mut dyn Writer {
+        self.len = 0;
+        self
+    }
+}
+
+unsafe impl<Words> Writer for FixedTape<Words>
+where
+    Words: AsMut<[MaybeUninit<usize>]>,
+{
+    #[inline(always)]
+    fn word_offset(&self) -> usize {
+        self.len
+    }
+
+    #[inline(always)]
+    fn take(&mut self, words: usize) -> Result<&mut [MaybeUninit<usize>], UnexpectedEndError> {
+        let start = self.len;
+        let slice = self
+            .words
+            .as_mut()
+            .get_mut(start..)
+            .and_then(|rest| rest.get_mut(..words))
+            .ok_or(UnexpectedEndError)?;
+        self.len = start + words;
+        Ok(slice)
+    }
+
+    #[inline(always)]
+    fn written_mut(&mut self) -> &mut [MaybeUninit<usize>] {
+        &mut self.words.as_mut()[..self.len]
+    }
+}
+
+/// The words backing a `FixedTape`.
+///
+/// # Safety
+///
+/// The slices returned by `as_ref` and `as_mut` must always start at an
+/// address aligned to `ALIGN`, even after the value moved.
+pub unsafe trait Storage: AsRef<[MaybeUninit<usize>]> + AsMut<[MaybeUninit<usize>]> {
+    /// The alignment of the first word.
+    const ALIGN: usize = mem::align_of::<usize>();
+}
+
+unsafe impl Storage for &mut [MaybeUninit<usize>] {}
+
+unsafe impl<const N: usize> Storage for [MaybeUninit<usize>; N] {}
+
+unsafe impl<const N: usize> Storage for AlignedWords<N> {
+    const ALIGN: usize = mem::align_of::<Self>();
+}
+
+unsafe impl<const N: usize> Storage for &mut AlignedWords<N> {
+    const ALIGN: usize = mem::align_of::<AlignedWords<N>>();
+}
+
+/// Words aligned to 64 bytes, to back tapes with over-aligned operations.
+///
+/// This is enough for operations holding `u128` values or SIMD vectors.
+#[derive(Clone, Copy)]
+#[repr(C, align(64))]
+pub struct AlignedWords<const N: usize>(pub [MaybeUninit<usize>; N]);
+
+impl<const N: usize> AsRef<[MaybeUninit<usize>]> for AlignedWords<N> {
+    #[inline(always)]
+    fn as_ref(&self) -> &[MaybeUninit<usize>] {
+        &self.0
+    }
+}
+
+impl<const N: usize> AsMut<[MaybeUninit<usize>]> for AlignedWords<N> {
+    #[inline(always)]
+    fn as_mut(&mut self) -> &mut [MaybeUninit<usize>] {
+        &mut self.0
+    }
+}
+
+/// The alignment of the tape in a `Vec<MaybeUninit<usize>>`.
+#[cfg(feature = "alloc")]
+const VEC_ALIGN: usize = mem::align_of::<AlignedWords<0>>();
+
+/// Returns the index of the first word of the vector's buffer aligned to
+/// `VEC_ALIGN`, where its tape starts.
+///
+/// The tape is empty as long as the vector is, so this index only matters
+/// once words were taken from it.
+#[cfg(feature = "alloc")]
+#[inline(always)]
+fn vec_start(vec: &[MaybeUninit<usize>]) -> usize {
+    let misalignment = vec.as_ptr() as usize & (VEC_ALIGN - 1);
+    ((VEC_ALIGN - misalignment) & (VEC_ALIGN - 1)) / mem::size_of::<usize>()
+}
+
 #[cfg(feature = "alloc")]
 unsafe impl AsClearedWriter for Vec<MaybeUninit<usize>> {
+    const ALIGN: usize = VEC_ALIGN;
+
     #[inline(always)]
     fn as_cleared_writer(&mut self) -> &mut dyn Writer {
         self.clear();
         self
     }
+
+    #[inline(always)]
+    fn written(&self) -> &[MaybeUninit<usize>] {
+        self.get(vec_start(self)..).unwrap_or(&[])
+    }
 }
 
 #[cfg(feature = "alloc")]
-unsafe impl<'tape> Writer for Vec<MaybeUninit<usize>> {
+unsafe impl Writer for Vec<MaybeUninit<usize>> {
     #[inline(always)]
     fn word_offset(&self) -> usize {
-        self.len()
+        self.len().saturating_sub(vec_start(self))
     }
 
-    #[inline(always)]
     fn take(&mut self, words: usize) -> Result<&mut [MaybeUninit<usize>], UnexpectedEndError> {
-        let len = self.len();
-        self.reserve(words);
+        let start = vec_start(self);
+        let offset = self.word_offset();
+        let end = offset.checked_add(words).ok_or(UnexpectedEndError)?;
+        // Leave room for the words skipped to align the tape, wherever the
+        // buffer ends up.
+        let capacity = end
+            .checked_add(VEC_ALIGN / mem::size_of::<usize>() - 1)
+            .ok_or(UnexpectedEndError)?;
+        self.reserve(capacity.saturating_sub(self.len()));
+        let new_start = vec_start(self);
         unsafe {
-            let slice = core::slice::from_raw_parts_mut(self.as_mut_ptr().add(len), words);
-            self.set_len(len + words);
-            Ok(slice)
+            let ptr = self.as_mut_ptr();
+            if offset != 0 && new_start != start {
+                // The buffer was reallocated at a different alignment, so
+                // the tape must be moved to the new start.
+                ptr::copy(ptr.add(start), ptr.add(new_start), offset);
+            }
+            self.set_len(new_start + end);
+            Ok(slice::from_raw_parts_mut(
+                ptr.add(new_start + offset),
+                words,
+            ))
         }
     }
+
+    #[inline(always)]
+    fn written_mut(&mut self) -> &mut [MaybeUninit<usize>] {
+        let start = vec_start(self);
+        self.get_mut(start..).unwrap_or(&mut [])
+    }
 }